@@ -0,0 +1,261 @@
+use std::{net::SocketAddr, time::UNIX_EPOCH};
+
+use axum::{
+    extract::{ConnectInfo, Path, Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::Serialize;
+use subtle::ConstantTimeEq;
+use tracing::{info, instrument};
+
+use crate::{
+    credentials::{hash_token, AuthError},
+    disconnect::DisconnectReason,
+    error::RelayError,
+    game_socket::release_game_server,
+    org::{Client, GameServer, Org},
+    scene::SceneUpdate,
+    simulation::physics::Impulse,
+    util, SharedState,
+};
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OrgSummary {
+    pub id: String,
+    pub client_count: usize,
+    pub item_count: usize,
+    pub game_servers: Vec<GameServerSummary>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GameServerSummary {
+    pub server_id: usize,
+    /// `false` while the server is disconnected and its session waits to be resumed
+    pub connected: bool,
+    pub owned_items: Vec<String>,
+    pub owned_prefix: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientSummary {
+    pub client_id: usize,
+    /// Unix timestamp in milliseconds
+    pub connected_at: u64,
+    pub queue_depth: usize,
+}
+
+impl From<&Org> for OrgSummary {
+    fn from(org: &Org) -> Self {
+        Self {
+            id: org.id.clone(),
+            client_count: org.clients.len(),
+            item_count: org.scene.items.len(),
+            game_servers: org
+                .game_servers
+                .iter()
+                .map(GameServerSummary::from)
+                .collect(),
+        }
+    }
+}
+
+impl From<&GameServer> for GameServerSummary {
+    fn from(game_server: &GameServer) -> Self {
+        Self {
+            server_id: game_server.server_id,
+            connected: !game_server.is_detached(),
+            owned_items: game_server.ownership.items.clone(),
+            owned_prefix: game_server.ownership.prefix.clone(),
+        }
+    }
+}
+
+impl From<&Client> for ClientSummary {
+    fn from(client: &Client) -> Self {
+        Self {
+            client_id: client.client_id,
+            connected_at: client
+                .connected_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            queue_depth: client.queue_depth(),
+        }
+    }
+}
+
+/// Routes to inspect and operate a running relay, mounted under `/admin` and only answering
+/// once `ADMIN_TOKEN` is set
+pub fn router(state: SharedState) -> Router<SharedState> {
+    Router::new()
+        .route("/orgs", get(list_orgs))
+        .route("/orgs/:org/clients", get(list_clients))
+        .route("/orgs/:org/clients/:client_id/kick", post(kick_client))
+        .route(
+            "/orgs/:org/game-servers/:server_id/disconnect",
+            post(disconnect_game_server),
+        )
+        .route("/orgs/:org/scene", delete(clear_scene))
+        .route("/orgs/:org/items/:item_id/impulse", post(push_impulse))
+        .layer(middleware::from_fn_with_state(state, require_admin))
+}
+
+const ADMIN_ENDPOINT: &str = "/admin";
+
+/// Checks the `authorization` header against the admin token, which is separate from the
+/// game server credentials. Failures count towards the same per ip lockout as game servers
+async fn require_admin(
+    State(state): State<SharedState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(admin_token_sha256) = &state.auth.admin_token_sha256 else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let ip = util::client_ip(&headers, addr, state.auth.trust_forwarded_for);
+    let lockout = &state.auth.lockout;
+    if let Some(locked_for) = lockout.ip_locked_for(ip) {
+        lockout.record_locked(ADMIN_ENDPOINT, ip, None, locked_for);
+        return RelayError::LockedOut(locked_for).into_response();
+    }
+
+    let result = match headers
+        .get("authorization")
+        .and_then(|header| header.to_str().ok())
+    {
+        None => Err(AuthError::MissingToken),
+        Some(token) => match bool::from(
+            hash_token(token)
+                .as_bytes()
+                .ct_eq(admin_token_sha256.as_bytes()),
+        ) {
+            true => Ok(()),
+            false => Err(AuthError::InvalidToken),
+        },
+    };
+
+    match result {
+        Ok(()) => {
+            lockout.record_success(ip, None);
+            next.run(request).await
+        }
+        Err(err) => {
+            lockout.record_failure(ADMIN_ENDPOINT, ip, None, err);
+            RelayError::from(err).into_response()
+        }
+    }
+}
+
+#[instrument(skip(state))]
+async fn list_orgs(State(state): State<SharedState>) -> Json<Vec<OrgSummary>> {
+    let current_orgs = state.orgs.lock().await;
+    Json(current_orgs.values().map(OrgSummary::from).collect())
+}
+
+#[instrument(skip(state))]
+async fn list_clients(
+    Path(org_id): Path<String>,
+    State(state): State<SharedState>,
+) -> Result<Json<Vec<ClientSummary>>, StatusCode> {
+    let current_orgs = state.orgs.lock().await;
+    let org = current_orgs.get(&org_id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(org.clients.iter().map(ClientSummary::from).collect()))
+}
+
+/// Closes a viewer's socket, its message task removes it from the org afterwards
+#[instrument(skip(state))]
+async fn kick_client(
+    Path((org_id, client_id)): Path<(String, usize)>,
+    State(state): State<SharedState>,
+) -> StatusCode {
+    let current_orgs = state.orgs.lock().await;
+    let Some(client) = current_orgs.get(&org_id).and_then(|org| {
+        org.clients
+            .iter()
+            .find(|client| client.client_id == client_id)
+    }) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    info!(client_id, "Kicking client");
+    let _ = client.send(DisconnectReason::KickedByAdmin.close_message());
+    StatusCode::NO_CONTENT
+}
+
+/// Closes a game server's socket and releases its session straight away, it cannot be resumed
+#[instrument(skip(state))]
+async fn disconnect_game_server(
+    Path((org_id, server_id)): Path<(String, usize)>,
+    State(state): State<SharedState>,
+) -> StatusCode {
+    let disconnect = state
+        .orgs
+        .lock()
+        .await
+        .get_mut(&org_id)
+        .and_then(|org| org.game_server_mut(server_id))
+        .map(|game_server| {
+            game_server.reattach();
+            game_server.disconnect.clone()
+        });
+    let Some(disconnect) = disconnect else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    info!(server_id, "Force disconnecting game server");
+    release_game_server(&org_id, server_id, state).await;
+    disconnect.notify_one();
+    StatusCode::NO_CONTENT
+}
+
+/// Despawns every item in the org scene and tells viewers to drop them
+#[instrument(skip(state))]
+async fn clear_scene(Path(org_id): Path<String>, State(state): State<SharedState>) -> StatusCode {
+    let mut current_orgs = state.orgs.lock().await;
+    let Some(org) = current_orgs.get_mut(&org_id) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    let despawned = org
+        .scene
+        .items
+        .iter()
+        .map(|item| SceneUpdate::despawn(item.id.clone()))
+        .collect::<Vec<SceneUpdate>>();
+    info!(despawned_count = despawned.len(), "Clearing scene");
+    if !despawned.is_empty() {
+        org.broadcast(despawned);
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// Pushes an item while the relay runs the physics simulation, it moves on the next step
+#[instrument(skip(state))]
+async fn push_impulse(
+    Path((org_id, item_id)): Path<(String, String)>,
+    State(state): State<SharedState>,
+    Json(impulse): Json<Impulse>,
+) -> Response {
+    let current_orgs = state.orgs.lock().await;
+    let Some(org) = current_orgs.get(&org_id) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    match org.push_impulse(&item_id, impulse) {
+        Ok(()) => {
+            info!("Pushing item");
+            StatusCode::ACCEPTED.into_response()
+        }
+        Err(err) => err.into_response(),
+    }
+}
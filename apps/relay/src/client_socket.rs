@@ -1,37 +1,168 @@
-use std::sync::atomic::AtomicUsize;
+use std::{net::SocketAddr, sync::atomic::AtomicUsize};
 
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        Path, State,
+        ConnectInfo, Path, Query, State,
     },
-    response::Response,
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
 };
-use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
-use tracing::{error, info, instrument};
+use tokio::select;
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    OwnedSemaphorePermit,
+};
+use tracing::{error, info, instrument, warn};
 
 use crate::{
-    org::{Client, Org},
-    util::ErrorFormatter,
+    disconnect::DisconnectReason,
+    error::{ErrorMessage, RelayError},
+    heartbeat::{Heartbeat, HeartbeatAction},
+    limits::{ConnectionPermit, QueuedViewer, ViewerSlot},
+    org::{self, Client},
+    scene::SceneUpdate,
+    shutdown, util,
+    viewer_auth::VIEWER_TOKEN_PROTOCOL,
     SharedState,
 };
 use futures_util::{future::select_all, sink::SinkExt, stream::StreamExt};
+use serde::{Deserialize, Serialize};
 
 static CLIENT_COUNT: AtomicUsize = AtomicUsize::new(0);
 
-#[instrument(skip(ws, state))]
+/// Messages sent to viewers, tagged by `type`. Scene updates arrive in `tick` messages
+#[derive(Serialize, Debug)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum ClientMessage {
+    /// Sent right before the relay closes the socket because it is shutting down
+    Restarting { reconnect_in_ms: u64 },
+    /// The org is at its viewer limit, scene updates start once a slot frees up. `position` is
+    /// 1 for the next viewer to be admitted.
+    Waiting { position: usize },
+    /// The relay rejected something the viewer sent
+    Error(ErrorMessage),
+    /// Sent every `throttle.message_interval_ms` with what changed since the previous tick, also
+    /// when nothing did. Viewers interpolate between ticks by their server time.
+    Tick {
+        tick: u64,
+        server_time_ms: u64,
+        updates: Vec<SceneUpdate>,
+    },
+}
+
+/// A viewer token can be passed as `/sub/:org?token=<token>`
+#[derive(Deserialize, Debug, Default)]
+pub struct ClientParams {
+    token: Option<String>,
+}
+
+#[instrument(skip(ws, state, params, headers))]
 pub async fn client_handler(
     ws: WebSocketUpgrade,
     Path(org_id): Path<String>,
+    Query(params): Query<ClientParams>,
     State(state): State<SharedState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
 ) -> Response {
-    // TODO log ip here
     info!(org_id, "Client establishing connection");
-    ws.on_upgrade(|socket| handle_client_socket(socket, org_id, state))
+
+    let origin = headers
+        .get(header::ORIGIN)
+        .map(|origin| origin.to_str().unwrap_or_default());
+    if !state.auth.origins.is_allowed(&org_id, origin) {
+        let ip = util::client_ip(&headers, addr, state.auth.trust_forwarded_for);
+        warn!(
+            target: "audit",
+            event = "origin_rejected",
+            endpoint = "/sub/:org",
+            %ip,
+            org_id,
+            origin,
+            "Rejected websocket from disallowed origin"
+        );
+        return RelayError::OriginNotAllowed.into_response();
+    }
+
+    if let Some(verifier) = &state.auth.viewer_tokens {
+        let token = params.token.or_else(|| token_from_protocols(&headers));
+        match verifier.verify(token.as_deref(), &org_id) {
+            Ok(claims) => info!(user_id = claims.sub, "Viewer authorized"),
+            Err(err) => {
+                let ip = util::client_ip(&headers, addr, state.auth.trust_forwarded_for);
+                warn!(
+                    target: "audit",
+                    event = "auth_failure",
+                    endpoint = "/sub/:org",
+                    %ip,
+                    org_id,
+                    reason = err.as_str(),
+                    "Authentication failed"
+                );
+                return RelayError::from(err).into_response();
+            }
+        }
+    }
+
+    let admitted = state
+        .limits
+        .admit(&*state.orgs.lock().await, &org_id)
+        .and_then(|permit| Ok((permit, state.limits.viewer_slot(&org_id)?)));
+    let (connection_permit, viewer_slot) = match admitted {
+        Ok(admitted) => admitted,
+        Err(err) => {
+            warn!(
+                org_id,
+                limit = err.as_str(),
+                "Client rejected by connection limits"
+            );
+            return RelayError::from(err).into_response();
+        }
+    };
+
+    ws.protocols([VIEWER_TOKEN_PROTOCOL]).on_upgrade(|socket| {
+        handle_client_socket(socket, org_id, state, connection_permit, viewer_slot)
+    })
+}
+
+/// Browsers cannot set headers on websockets, so the token may be offered as the subprotocol
+/// following `viewer-token`
+fn token_from_protocols(headers: &HeaderMap) -> Option<String> {
+    let protocols = headers
+        .get(header::SEC_WEBSOCKET_PROTOCOL)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .map(str::trim)
+        .collect::<Vec<&str>>();
+    protocols
+        .iter()
+        .position(|protocol| *protocol == VIEWER_TOKEN_PROTOCOL)
+        .and_then(|index| protocols.get(index + 1))
+        .map(|token| token.to_string())
 }
 
-#[instrument(skip(ws, state))]
-async fn handle_client_socket(ws: WebSocket, org_id: String, state: SharedState) {
+#[instrument(skip(ws, state, _connection_permit, viewer_slot))]
+async fn handle_client_socket(
+    mut ws: WebSocket,
+    org_id: String,
+    state: SharedState,
+    _connection_permit: ConnectionPermit,
+    viewer_slot: ViewerSlot,
+) {
+    let _viewer_permit = match viewer_slot {
+        ViewerSlot::Admitted(permit) => permit,
+        ViewerSlot::Queued(queued) => match wait_for_viewer_slot(&mut ws, queued, &state).await {
+            Some(permit) => Some(permit),
+            None => return,
+        },
+    };
+
     let (mut ws_tx, mut ws_rx) = ws.split();
     let (tx, mut incoming_messages_rx): (UnboundedSender<Message>, UnboundedReceiver<Message>) =
         mpsc::unbounded_channel();
@@ -39,30 +170,43 @@ async fn handle_client_socket(ws: WebSocket, org_id: String, state: SharedState)
 
     info!(client_id, "New client connected");
 
+    let client = Client::new(client_id, tx);
+    let stored_scene = org::load_stored_scene(&org_id, &state).await;
     let mut current_orgs = state.orgs.lock().await;
-    current_orgs
-        .entry(org_id.clone())
-        .or_insert_with(|| Org::new(vec![], true, org_id.clone()))
+    org::join_org(&mut current_orgs, &org_id, stored_scene, &state)
         .clients
-        .push(Client { tx, client_id });
+        .push(client.clone());
 
     drop(current_orgs);
 
     let state_for_message_task = state.clone();
     let org_id_for_message_task = org_id.clone();
+    let client_for_message_task = client.clone();
     let message_task = tokio::spawn(async move {
         while let Some(msg) = incoming_messages_rx.recv().await {
+            client_for_message_task.message_dequeued();
             match msg {
-                msg @ Message::Text(_) => {
+                msg @ (Message::Text(_) | Message::Ping(_)) => {
                     if let Err(err) = ws_tx.send(msg).await {
+                        let err = RelayError::from(err);
                         error!(
                             client_id,
-                            error = ErrorFormatter::format_axum_error(err),
+                            code = err.code(),
+                            error = %err,
                             "Error sending message"
                         );
                     }
                 }
-                Message::Close(_) => {
+                msg @ Message::Close(_) => {
+                    if let Err(err) = ws_tx.send(msg).await {
+                        let err = RelayError::from(err);
+                        error!(
+                            client_id,
+                            code = err.code(),
+                            error = %err,
+                            "Error sending close frame"
+                        );
+                    }
                     info!(client_id, "Client disconnected",);
 
                     remove_client(&org_id_for_message_task, client_id, state_for_message_task)
@@ -76,8 +220,34 @@ async fn handle_client_socket(ws: WebSocket, org_id: String, state: SharedState)
 
     let state_for_disconnect_task = state.clone();
     let org_id_for_disconnect_task = org_id.clone();
+    let mut heartbeat = Heartbeat::new(state.client_heartbeat);
     let disconnect_task = tokio::spawn(async move {
-        while let Some(msg) = ws_rx.next().await {
+        let mut is_closing = false;
+        loop {
+            let msg = select! {
+                msg = ws_rx.next() => msg,
+                action = heartbeat.next_action(), if !is_closing => {
+                    match action {
+                        HeartbeatAction::SendPing => {
+                            let _ = client.send(Message::Ping(vec![]));
+                        }
+                        HeartbeatAction::Disconnect(reason) => {
+                            warn!(client_id, reason = reason.as_str(), "Disconnecting client");
+                            // The message task sends the close frame and removes the client
+                            let _ = client.send(reason.close_message());
+                            is_closing = true;
+                        }
+                    }
+                    continue;
+                }
+            };
+            let Some(msg) = msg else {
+                break;
+            };
+            if let Ok(msg) = &msg {
+                heartbeat.received(msg);
+            }
+
             match msg {
                 Ok(Message::Close(_)) => {
                     let client_count = remove_client(
@@ -91,13 +261,25 @@ async fn handle_client_socket(ws: WebSocket, org_id: String, state: SharedState)
                     return;
                 }
                 Ok(Message::Text(incoming_message)) => {
-                    info!(client_id, incoming_message, "Message from client",);
+                    let err = RelayError::UnexpectedMessage;
+                    info!(
+                        client_id,
+                        incoming_message,
+                        code = err.code(),
+                        "Message from client"
+                    );
+                    let unexpected =
+                        serde_json::to_string(&ClientMessage::Error(err.to_message(None)))
+                            .expect("Failed to serialize message");
+                    let _ = client.send(Message::Text(unexpected));
                 }
                 Ok(_) => continue,
                 Err(err) => {
+                    let err = RelayError::from(err);
                     error!(
                         client_id,
-                        error = ErrorFormatter::format_axum_error(err),
+                        code = err.code(),
+                        error = %err,
                         "Error receiving message"
                     );
                 }
@@ -108,11 +290,13 @@ async fn handle_client_socket(ws: WebSocket, org_id: String, state: SharedState)
     let remaining_tasks = match select_all(vec![message_task, disconnect_task]).await {
         (Ok(_), _, remaining) => remaining,
         (Err(err), index, remaining) => {
+            let err = RelayError::from(err);
             error!(
                 org_id,
                 client_id,
                 index,
-                error = ErrorFormatter::format_join_error(err),
+                code = err.code(),
+                error = %err,
                 "Error in ws handling task"
             );
             remaining
@@ -123,9 +307,82 @@ async fn handle_client_socket(ws: WebSocket, org_id: String, state: SharedState)
         task.abort();
     }
 
-    let mut current_orgs = state.orgs.lock().await;
-    if let Some(org) = current_orgs.get_mut(&org_id) {
-        org.clients.retain(|client| client.client_id != client_id);
+    remove_client(&org_id, client_id, state).await;
+}
+
+/// Keeps a viewer over the org's limit connected until a slot frees up, returns `None` when it
+/// leaves or the relay shuts down first
+#[instrument(skip(ws, queued, state))]
+async fn wait_for_viewer_slot(
+    ws: &mut WebSocket,
+    queued: QueuedViewer,
+    state: &SharedState,
+) -> Option<OwnedSemaphorePermit> {
+    info!(position = queued.position, "Viewer waiting for a free slot");
+    let waiting = serde_json::to_string(&ClientMessage::Waiting {
+        position: queued.position,
+    })
+    .expect("Failed to serialize message");
+    if let Err(err) = ws.send(Message::Text(waiting)).await {
+        let err = RelayError::from(err);
+        error!(
+            code = err.code(),
+            error = %err,
+            "Error sending waiting message"
+        );
+        return None;
+    }
+
+    let mut heartbeat = Heartbeat::new(state.client_heartbeat);
+    let mut shutdown = state.shutdown.subscribe();
+    let admitted = queued.admitted();
+    tokio::pin!(admitted);
+    loop {
+        let message = select! {
+            permit = &mut admitted => {
+                info!("Viewer admitted from the waiting room");
+                return Some(permit);
+            }
+            _ = shutdown::wait_for_shutdown(&mut shutdown) => {
+                let restarting = serde_json::to_string(&ClientMessage::Restarting {
+                    reconnect_in_ms: state.lifecycle.reconnect_delay.as_millis() as u64,
+                })
+                .expect("Failed to serialize message");
+                let _ = ws.send(Message::Text(restarting)).await;
+                DisconnectReason::ServerRestarting.close_message()
+            }
+            msg = ws.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    info!("Viewer left the waiting room");
+                    return None;
+                }
+                Some(Ok(msg)) => {
+                    heartbeat.received(&msg);
+                    continue;
+                }
+            },
+            action = heartbeat.next_action() => match action {
+                HeartbeatAction::SendPing => Message::Ping(vec![]),
+                HeartbeatAction::Disconnect(reason) => {
+                    warn!(reason = reason.as_str(), "Disconnecting waiting viewer");
+                    reason.close_message()
+                }
+            },
+        };
+
+        let is_close = matches!(message, Message::Close(_));
+        if let Err(err) = ws.send(message).await {
+            let err = RelayError::from(err);
+            error!(
+                code = err.code(),
+                error = %err,
+                "Error sending message to waiting viewer"
+            );
+            return None;
+        }
+        if is_close {
+            return None;
+        }
     }
 }
 
@@ -134,12 +391,43 @@ async fn remove_client(org_id: &String, client_id: usize, state: SharedState) ->
     let mut current_orgs = state.orgs.lock().await;
     if let Some(org) = current_orgs.get_mut(org_id) {
         org.clients.retain(|client| client.client_id != client_id);
-        let client_count = org.clients.len();
-        if client_count == 0 && !org.server_connected {
-            current_orgs.remove(org_id);
-            return Some(client_count);
-        }
-        return Some(client_count);
+        org::schedule_reap_if_idle(org, &state);
+        return Some(org.clients.len());
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn token(protocols: &str) -> Option<String> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_str(protocols).unwrap(),
+        );
+        token_from_protocols(&headers)
+    }
+
+    #[test]
+    fn reads_token_after_viewer_protocol() {
+        assert_eq!(
+            token("viewer-token, abc.def.ghi").as_deref(),
+            Some("abc.def.ghi")
+        );
+        assert_eq!(
+            token("chat,viewer-token,abc.def.ghi, other").as_deref(),
+            Some("abc.def.ghi")
+        );
+    }
+
+    #[test]
+    fn no_token_without_viewer_protocol() {
+        assert_eq!(token("chat, abc.def.ghi"), None);
+        assert_eq!(token("abc.def.ghi, viewer-token"), None);
+        assert_eq!(token_from_protocols(&HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,706 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::Parser;
+use serde::{Deserialize, Deserializer};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    game_socket::ThrottleConfig,
+    heartbeat::HeartbeatConfig,
+    limits::LimitsConfig,
+    lockout::LockoutConfig,
+    origin::OriginConfig,
+    scripting::ScriptConfig,
+    simulation::{Registry, SimulationConfig},
+    tls::TlsConfig,
+    validation::ValidationConfig,
+    LifecycleConfig,
+};
+
+const DEFAULT_PORT: u16 = 3002;
+const DEFAULT_CREDENTIALS_RELOAD_INTERVAL_MS: u64 = 5_000;
+
+/// Relays scene updates from game servers to the viewers of an org
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// TOML config file, env vars and flags override the values in it
+    #[arg(short, long, env = "RELAY_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on
+    #[arg(long)]
+    pub host: Option<IpAddr>,
+
+    /// Port to listen on
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// Feed every org from the built in simulation
+    #[arg(long)]
+    pub simulate: bool,
+
+    /// `development` logs plain output to stdout without axiom
+    #[arg(long)]
+    pub env: Option<Environment>,
+
+    /// Log filter in the `RUST_LOG` syntax
+    #[arg(long)]
+    pub log_filter: Option<String>,
+
+    /// Validate the configuration and exit
+    #[arg(long)]
+    pub check_config: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    Development,
+    #[default]
+    Production,
+}
+
+impl FromStr for Environment {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "development" => Ok(Environment::Development),
+            "production" => Ok(Environment::Production),
+            _ => Err("expected development or production".into()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub throttle: ThrottleConfig,
+    pub limits: LimitsConfig,
+    pub validation: ValidationConfig,
+    pub simulation: SimulationConfig,
+    pub scripts: ScriptConfig,
+    pub lifecycle: LifecycleConfig,
+    pub heartbeat: HeartbeatConfigs,
+    pub auth: AuthConfig,
+    pub origins: OriginConfig,
+    pub storage: StorageConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: IpAddr,
+    pub port: u16,
+    pub simulate: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: DEFAULT_PORT,
+            simulate: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfigs {
+    pub client: HeartbeatConfig,
+    pub game_server: HeartbeatConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Single game server token with access to every org, ignored when `credentials_file` is set
+    pub token: Option<String>,
+    pub credentials_file: Option<PathBuf>,
+    #[serde(rename = "credentials_reload_interval_ms", deserialize_with = "millis")]
+    pub credentials_reload_interval: Duration,
+    /// HS256 secret shared with the web app for viewer tokens
+    pub viewer_token_secret: Option<String>,
+    /// Ed25519 public key for viewer tokens, used when no secret is set
+    pub viewer_token_public_key_file: Option<PathBuf>,
+    pub admin_token: Option<String>,
+    pub trust_forwarded_for: bool,
+    pub lockout: LockoutConfig,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            credentials_file: None,
+            credentials_reload_interval: Duration::from_millis(
+                DEFAULT_CREDENTIALS_RELOAD_INTERVAL_MS,
+            ),
+            viewer_token_secret: None,
+            viewer_token_public_key_file: None,
+            admin_token: None,
+            trust_forwarded_for: false,
+            lockout: LockoutConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Scenes are persisted here on shutdown, they only live in memory when unset
+    pub scene_dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub env: Environment,
+    /// Defaults to `debug` outside of development
+    pub filter: Option<String>,
+    pub axiom_token: Option<String>,
+    pub axiom_dataset: Option<String>,
+    pub deployment_id: Option<String>,
+    pub replica_id: Option<String>,
+}
+
+/// Reads durations given in milliseconds, config keys holding one end in `_ms`
+pub fn millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+/// Collects every env var that fails to parse instead of stopping at the first one
+struct EnvOverrides<'a> {
+    problems: &'a mut Vec<String>,
+    deprecations: &'a mut Vec<String>,
+}
+
+impl EnvOverrides<'_> {
+    fn read<T: FromStr>(&mut self, name: &str) -> Option<T>
+    where
+        T::Err: std::fmt::Display,
+    {
+        let value = std::env::var(name).ok()?;
+        value
+            .parse()
+            .map_err(|err| self.problems.push(format!("{}={:?}: {}", name, value, err)))
+            .ok()
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &str, field: &mut T)
+    where
+        T::Err: std::fmt::Display,
+    {
+        if let Some(value) = self.read(name) {
+            *field = value;
+        }
+    }
+
+    fn optional<T: FromStr>(&mut self, name: &str, field: &mut Option<T>)
+    where
+        T::Err: std::fmt::Display,
+    {
+        if let Some(value) = self.read(name) {
+            *field = Some(value);
+        }
+    }
+
+    /// Comma separated values, empty entries are skipped
+    fn list<T: FromStr>(&mut self, name: &str, field: &mut Vec<T>)
+    where
+        T::Err: std::fmt::Display,
+    {
+        let Ok(value) = std::env::var(name) else {
+            return;
+        };
+        let entries = value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| entry.parse())
+            .collect::<Result<Vec<T>, _>>();
+        match entries {
+            Ok(entries) => *field = entries,
+            Err(err) => self.problems.push(format!("{}={:?}: {}", name, value, err)),
+        }
+    }
+
+    /// `ENV` used to mean production for anything but `development`, other values still do
+    /// but are reported as deprecated
+    fn environment(&mut self, name: &str, field: &mut Environment) {
+        let Ok(value) = std::env::var(name) else {
+            return;
+        };
+        *field = match value.to_ascii_lowercase().parse() {
+            Ok(environment) => environment,
+            Err(_) => {
+                self.deprecations.push(format!(
+                    "{}={:?} is treated as production, set it to development or production",
+                    name, value
+                ));
+                Environment::Production
+            }
+        };
+    }
+
+    /// Switches like `SIMULATE`, `true` or `1` turn them on and `false`, `0` or an empty value
+    /// turn them off, anything else is a problem
+    fn flag(&mut self, name: &str, field: &mut bool) {
+        let Ok(value) = std::env::var(name) else {
+            return;
+        };
+        match value.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" => *field = true,
+            "false" | "0" | "" => *field = false,
+            _ => self.problems.push(format!(
+                "{}={:?}: expected true, false, 1 or 0",
+                name, value
+            )),
+        }
+    }
+
+    fn millis(&mut self, name: &str, field: &mut Duration) {
+        if let Some(ms) = self.read(name) {
+            *field = Duration::from_millis(ms);
+        }
+    }
+
+    fn heartbeat(&mut self, prefix: &str, heartbeat: &mut HeartbeatConfig) {
+        self.millis(
+            &format!("{}_PING_INTERVAL_MS", prefix),
+            &mut heartbeat.ping_interval,
+        );
+        self.millis(
+            &format!("{}_PONG_TIMEOUT_MS", prefix),
+            &mut heartbeat.pong_timeout,
+        );
+        self.millis(
+            &format!("{}_IDLE_TIMEOUT_MS", prefix),
+            &mut heartbeat.idle_timeout,
+        );
+    }
+}
+
+impl Config {
+    /// Builds the config from defaults, the config file, env vars and flags, in that order of
+    /// precedence, and exits listing every problem when it is invalid
+    pub fn load() -> Self {
+        let args = Args::parse();
+        let mut problems = vec![];
+        let mut deprecations = vec![];
+
+        let mut config = match &args.config {
+            None => Config::default(),
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(contents) => toml::from_str(&contents).unwrap_or_else(|err| {
+                    problems.push(format!("{}: {}", path.display(), err));
+                    Config::default()
+                }),
+                Err(err) => {
+                    problems.push(format!("{}: {}", path.display(), err));
+                    Config::default()
+                }
+            },
+        };
+        let file_problems = problems.len();
+        config.apply_env(&mut EnvOverrides {
+            problems: &mut problems,
+            deprecations: &mut deprecations,
+        });
+        config.apply_args(&args);
+        // Validating the defaults a broken file fell back to would only report noise
+        if file_problems == 0 {
+            problems.extend(config.validate());
+        }
+
+        // Logging is only set up from the loaded config, so these go straight to stderr
+        for deprecation in deprecations {
+            eprintln!("Deprecated relay configuration: {}", deprecation);
+        }
+        if !problems.is_empty() {
+            eprintln!("Invalid relay configuration:");
+            for problem in problems {
+                eprintln!("  - {}", problem);
+            }
+            std::process::exit(2);
+        }
+        if args.check_config {
+            println!("Configuration is valid");
+            std::process::exit(0);
+        }
+        config
+    }
+
+    fn apply_env(&mut self, env: &mut EnvOverrides) {
+        env.parse("HOST", &mut self.server.host);
+        env.parse("PORT", &mut self.server.port);
+        env.flag("SIMULATE", &mut self.server.simulate);
+
+        env.optional("TLS_CERT_FILE", &mut self.tls.cert_file);
+        env.optional("TLS_KEY_FILE", &mut self.tls.key_file);
+        env.millis("TLS_RELOAD_INTERVAL_MS", &mut self.tls.reload_interval);
+
+        env.millis("MESSAGE_THROTTLE_MS", &mut self.throttle.message_interval);
+        env.millis("SIM_THROTTLE_MS", &mut self.throttle.simulation_interval);
+        env.millis("TICK_TIMEOUT_MS", &mut self.throttle.tick_timeout);
+
+        env.optional("MAX_VIEWERS_PER_ORG", &mut self.limits.max_viewers_per_org);
+        env.optional("MAX_ORGS", &mut self.limits.max_orgs);
+        env.optional("MAX_CONNECTIONS", &mut self.limits.max_connections);
+        env.parse("VIEWER_WAITING_ROOM", &mut self.limits.waiting_room);
+        let game_server = &mut self.limits.game_server;
+        env.parse(
+            "GAME_SERVER_MAX_FRAME_BYTES",
+            &mut game_server.max_frame_bytes,
+        );
+        env.parse(
+            "GAME_SERVER_UPDATES_PER_SECOND",
+            &mut game_server.updates_per_second,
+        );
+        env.parse("GAME_SERVER_UPDATE_BURST", &mut game_server.update_burst);
+        env.parse(
+            "GAME_SERVER_MAX_ITEMS_PER_BATCH",
+            &mut game_server.max_items_per_batch,
+        );
+
+        env.optional("WORLD_BOUNDS", &mut self.validation.world_bounds);
+        env.parse("UNKNOWN_ITEMS", &mut self.validation.unknown_items);
+        env.parse("MAX_ITEM_ID_LENGTH", &mut self.validation.max_id_length);
+
+        env.optional("SIMULATION_SEED", &mut self.simulation.seed);
+        env.parse("SIMULATION_MODE", &mut self.simulation.mode);
+        env.parse("SIMULATION_ENGINE", &mut self.simulation.engine);
+        env.list("SIMULATION_BEHAVIOURS", &mut self.simulation.behaviours);
+        let physics = &mut self.simulation.physics;
+        env.parse("PHYSICS_GRAVITY", &mut physics.gravity);
+        env.parse("PHYSICS_RESTITUTION", &mut physics.restitution);
+        env.parse("PHYSICS_FRICTION", &mut physics.friction);
+        env.parse("PHYSICS_LINEAR_DAMPING", &mut physics.linear_damping);
+        env.parse("PHYSICS_ANGULAR_DAMPING", &mut physics.angular_damping);
+        env.parse("PHYSICS_GROUND_Y", &mut physics.ground_y);
+
+        let scripts = &mut self.scripts;
+        env.optional("SCRIPT_DIR", &mut scripts.dir);
+        env.millis("SCRIPT_RELOAD_INTERVAL_MS", &mut scripts.reload_interval);
+        env.parse("SCRIPT_MAX_OPERATIONS", &mut scripts.max_operations);
+        env.parse("SCRIPT_MAX_CALL_DEPTH", &mut scripts.max_call_depth);
+        env.parse("SCRIPT_MAX_STRING_SIZE", &mut scripts.max_string_size);
+        env.parse(
+            "SCRIPT_MAX_COLLECTION_SIZE",
+            &mut scripts.max_collection_size,
+        );
+
+        env.millis("ORG_GRACE_PERIOD_MS", &mut self.lifecycle.org_grace_period);
+        env.millis(
+            "GAME_SERVER_RESUME_TIMEOUT_MS",
+            &mut self.lifecycle.resume_timeout,
+        );
+        env.millis(
+            "SHUTDOWN_DEADLINE_MS",
+            &mut self.lifecycle.shutdown_deadline,
+        );
+        env.millis("RECONNECT_DELAY_MS", &mut self.lifecycle.reconnect_delay);
+        env.millis("UNREADY_DELAY_MS", &mut self.lifecycle.unready_delay);
+
+        env.heartbeat("CLIENT", &mut self.heartbeat.client);
+        env.heartbeat("GAME_SERVER", &mut self.heartbeat.game_server);
+
+        let auth = &mut self.auth;
+        env.optional("AUTH_TOKEN", &mut auth.token);
+        env.optional("CREDENTIALS_FILE", &mut auth.credentials_file);
+        env.millis(
+            "CREDENTIALS_RELOAD_INTERVAL_MS",
+            &mut auth.credentials_reload_interval,
+        );
+        env.optional("VIEWER_TOKEN_SECRET", &mut auth.viewer_token_secret);
+        env.optional(
+            "VIEWER_TOKEN_PUBLIC_KEY_FILE",
+            &mut auth.viewer_token_public_key_file,
+        );
+        env.optional("ADMIN_TOKEN", &mut auth.admin_token);
+        env.parse("TRUST_FORWARDED_FOR", &mut auth.trust_forwarded_for);
+        env.parse("AUTH_LOCKOUT_IP_THRESHOLD", &mut auth.lockout.ip_threshold);
+        env.parse(
+            "AUTH_LOCKOUT_ORG_THRESHOLD",
+            &mut auth.lockout.org_threshold,
+        );
+        env.millis("AUTH_LOCKOUT_BASE_MS", &mut auth.lockout.base_lockout);
+        env.millis("AUTH_LOCKOUT_MAX_MS", &mut auth.lockout.max_lockout);
+
+        env.list("ALLOWED_ORIGINS", &mut self.origins.allowed);
+
+        env.optional("SCENE_STORAGE_DIR", &mut self.storage.scene_dir);
+
+        let logging = &mut self.logging;
+        env.environment("ENV", &mut logging.env);
+        env.optional("RUST_LOG", &mut logging.filter);
+        env.optional("AXIOM_TOKEN", &mut logging.axiom_token);
+        env.optional("AXIOM_DATASET", &mut logging.axiom_dataset);
+        env.optional("RAILWAY_DEPLOYMENT_ID", &mut logging.deployment_id);
+        env.optional("RAILWAY_REPLICA_ID", &mut logging.replica_id);
+    }
+
+    fn apply_args(&mut self, args: &Args) {
+        if let Some(host) = args.host {
+            self.server.host = host;
+        }
+        if let Some(port) = args.port {
+            self.server.port = port;
+        }
+        if args.simulate {
+            self.server.simulate = true;
+        }
+        if let Some(env) = args.env {
+            self.logging.env = env;
+        }
+        if let Some(filter) = &args.log_filter {
+            self.logging.filter = Some(filter.clone());
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let mut check = |is_valid: bool, problem: &str| {
+            if !is_valid {
+                problems.push(problem.to_string());
+            }
+        };
+
+        let tls = &self.tls;
+        check(
+            tls.cert_file.is_some() == tls.key_file.is_some(),
+            "tls.cert_file and tls.key_file (TLS_CERT_FILE and TLS_KEY_FILE) must be set together",
+        );
+        for (name, path) in [("cert_file", &tls.cert_file), ("key_file", &tls.key_file)] {
+            if let Some(path) = path {
+                check(
+                    path.is_file(),
+                    &format!("tls.{} {} does not exist", name, path.display()),
+                );
+            }
+        }
+        check(
+            !tls.reload_interval.is_zero(),
+            "tls.reload_interval_ms must be greater than 0",
+        );
+
+        check(
+            !self.throttle.message_interval.is_zero(),
+            "throttle.message_interval_ms must be greater than 0",
+        );
+        check(
+            !self.throttle.simulation_interval.is_zero(),
+            "throttle.simulation_interval_ms must be greater than 0",
+        );
+        check(
+            !self.throttle.tick_timeout.is_zero(),
+            "throttle.tick_timeout_ms must be greater than 0",
+        );
+
+        for (name, limit) in [
+            ("max_viewers_per_org", self.limits.max_viewers_per_org),
+            ("max_orgs", self.limits.max_orgs),
+            ("max_connections", self.limits.max_connections),
+        ] {
+            check(
+                limit != Some(0),
+                &format!(
+                    "limits.{} must be greater than 0, leave it unset for no limit",
+                    name
+                ),
+            );
+        }
+
+        let game_server = &self.limits.game_server;
+        for (name, limit) in [
+            ("max_frame_bytes", game_server.max_frame_bytes),
+            (
+                "updates_per_second",
+                game_server.updates_per_second as usize,
+            ),
+            ("update_burst", game_server.update_burst as usize),
+            ("max_items_per_batch", game_server.max_items_per_batch),
+        ] {
+            check(
+                limit > 0,
+                &format!("limits.game_server.{} must be greater than 0", name),
+            );
+        }
+
+        check(
+            self.validation
+                .world_bounds
+                .is_none_or(|bounds| bounds.is_valid()),
+            "validation.world_bounds must be finite with min at most max on every axis",
+        );
+        check(
+            self.validation.max_id_length > 0,
+            "validation.max_id_length must be greater than 0",
+        );
+        for problem in self.simulation.invalid_behaviours(&Registry::built_in()) {
+            check(false, &problem);
+        }
+        let physics = &self.simulation.physics;
+        check(
+            physics.gravity.is_finite(),
+            "simulation.physics.gravity must be finite",
+        );
+        check(
+            physics.ground_y.is_finite(),
+            "simulation.physics.ground_y must be finite",
+        );
+        check(
+            (0.0..=1.0).contains(&physics.restitution),
+            "simulation.physics.restitution must be between 0 and 1",
+        );
+        for (name, value) in [
+            ("friction", physics.friction),
+            ("linear_damping", physics.linear_damping),
+            ("angular_damping", physics.angular_damping),
+        ] {
+            check(
+                value.is_finite() && value >= 0.0,
+                &format!("simulation.physics.{} must be 0 or more", name),
+            );
+        }
+
+        let scripts = &self.scripts;
+        if let Some(dir) = &scripts.dir {
+            check(
+                dir.is_dir(),
+                &format!("scripts.dir {} does not exist", dir.display()),
+            );
+        }
+        check(
+            !scripts.reload_interval.is_zero(),
+            "scripts.reload_interval_ms must be greater than 0",
+        );
+        for (name, limit) in [
+            ("max_operations", scripts.max_operations as usize),
+            ("max_call_depth", scripts.max_call_depth),
+            ("max_string_size", scripts.max_string_size),
+            ("max_collection_size", scripts.max_collection_size),
+        ] {
+            check(
+                limit > 0,
+                &format!("scripts.{} must be greater than 0", name),
+            );
+        }
+
+        for (name, heartbeat) in [
+            ("client", &self.heartbeat.client),
+            ("game_server", &self.heartbeat.game_server),
+        ] {
+            check(
+                !heartbeat.ping_interval.is_zero(),
+                &format!("heartbeat.{}.ping_interval_ms must be greater than 0", name),
+            );
+            check(
+                heartbeat.idle_timeout > heartbeat.ping_interval,
+                &format!(
+                    "heartbeat.{}.idle_timeout_ms must be greater than ping_interval_ms, \
+                     otherwise quiet peers are dropped before they are pinged",
+                    name
+                ),
+            );
+        }
+
+        let auth = &self.auth;
+        check(
+            auth.token.is_some() || auth.credentials_file.is_some(),
+            "auth.token or auth.credentials_file (AUTH_TOKEN or CREDENTIALS_FILE) must be set",
+        );
+        if let Some(path) = &auth.credentials_file {
+            check(
+                path.is_file(),
+                &format!("auth.credentials_file {} does not exist", path.display()),
+            );
+        }
+        if let Some(path) = &auth.viewer_token_public_key_file {
+            check(
+                path.is_file(),
+                &format!(
+                    "auth.viewer_token_public_key_file {} does not exist",
+                    path.display()
+                ),
+            );
+        }
+        check(
+            !auth.credentials_reload_interval.is_zero(),
+            "auth.credentials_reload_interval_ms must be greater than 0",
+        );
+        check(
+            auth.lockout.ip_threshold > 0,
+            "auth.lockout.ip_threshold must be greater than 0",
+        );
+        check(
+            auth.lockout.org_threshold > 0,
+            "auth.lockout.org_threshold must be greater than 0",
+        );
+        check(
+            auth.lockout.base_lockout <= auth.lockout.max_lockout,
+            "auth.lockout.base_ms must not be greater than auth.lockout.max_ms",
+        );
+
+        for origin in self.origins.invalid_origins() {
+            check(
+                false,
+                &format!(
+                    "origins: {:?} is not an origin like https://example.com",
+                    origin
+                ),
+            );
+        }
+
+        if let Some(filter) = &self.logging.filter {
+            if let Err(err) = EnvFilter::try_new(filter) {
+                check(false, &format!("logging.filter {:?}: {}", filter, err));
+            }
+        }
+
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn simulate(value: &str) -> (bool, Vec<String>) {
+        let mut problems = vec![];
+        let mut deprecations = vec![];
+        let mut config = Config::default();
+        std::env::set_var("SIMULATE", value);
+        config.apply_env(&mut EnvOverrides {
+            problems: &mut problems,
+            deprecations: &mut deprecations,
+        });
+        std::env::remove_var("SIMULATE");
+        (config.server.simulate, problems)
+    }
+
+    #[test]
+    fn simulate_env_override() {
+        for value in ["true", "TRUE", "1"] {
+            assert_eq!(simulate(value), (true, vec![]), "{:?}", value);
+        }
+        for value in ["false", "False", "0", ""] {
+            assert_eq!(simulate(value), (false, vec![]), "{:?}", value);
+        }
+        for value in ["yes", "on", "2"] {
+            let (simulate, problems) = simulate(value);
+            assert!(!simulate, "{:?}", value);
+            assert_eq!(problems.len(), 1, "{:?}", value);
+        }
+    }
+}
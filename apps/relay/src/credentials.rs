@@ -0,0 +1,339 @@
+use std::{
+    path::PathBuf,
+    sync::RwLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use axum::http::StatusCode;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::time::sleep;
+use tracing::{error, info, instrument};
+
+use crate::SharedState;
+
+/// Org id that grants access to every org
+const ANY_ORG: &str = "*";
+
+/// What a game server credential is allowed to do
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Connect to `/game/:org` and publish scene updates
+    Publish,
+    /// Claim ownership of items with `items` or `prefix`
+    Claim,
+}
+
+/// A game server credential as stored in the credentials file. Only the sha256 of the token is
+/// stored, several entries with the same name can coexist while a token is being rotated.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Credential {
+    pub name: String,
+    /// Hex encoded sha256 of the token
+    pub token_sha256: String,
+    /// Org ids this token may publish into, `*` allows every org
+    pub orgs: Vec<String>,
+    pub scopes: Vec<Scope>,
+    /// Unix timestamp in seconds after which the token is rejected
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CredentialsFile {
+    credentials: Vec<Credential>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken,
+    ExpiredToken,
+    OrgNotAllowed,
+    ScopeNotAllowed(Scope),
+}
+
+impl AuthError {
+    /// Unknown or expired tokens are unauthenticated, valid tokens lacking access are forbidden
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::MissingToken | AuthError::InvalidToken | AuthError::ExpiredToken => {
+                StatusCode::UNAUTHORIZED
+            }
+            AuthError::OrgNotAllowed | AuthError::ScopeNotAllowed(_) => StatusCode::FORBIDDEN,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthError::MissingToken => "missing token",
+            AuthError::InvalidToken => "invalid token",
+            AuthError::ExpiredToken => "token expired",
+            AuthError::OrgNotAllowed => "token is not allowed to access this org",
+            AuthError::ScopeNotAllowed(Scope::Publish) => "token is missing the publish scope",
+            AuthError::ScopeNotAllowed(Scope::Claim) => "token is missing the claim scope",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CredentialStore {
+    path: Option<PathBuf>,
+    credentials: RwLock<Vec<Credential>>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl CredentialStore {
+    /// A single token with access to every org, used when no credentials file is configured
+    pub fn from_token(token: &str) -> Self {
+        Self {
+            path: None,
+            credentials: RwLock::new(vec![Credential {
+                name: "AUTH_TOKEN".into(),
+                token_sha256: hash_token(token),
+                orgs: vec![ANY_ORG.into()],
+                scopes: vec![Scope::Publish, Scope::Claim],
+                expires_at: None,
+            }]),
+            modified: RwLock::new(None),
+        }
+    }
+
+    pub fn from_file(path: PathBuf) -> anyhow::Result<Self> {
+        let store = Self {
+            path: Some(path),
+            credentials: RwLock::new(vec![]),
+            modified: RwLock::new(None),
+        };
+        store.reload()?;
+        Ok(store)
+    }
+
+    fn reload(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let modified = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Failed to read credentials file {:?}", path))?;
+        if *self.modified.read().unwrap() == Some(modified) {
+            return Ok(());
+        }
+
+        let contents = std::fs::read(path)
+            .with_context(|| format!("Failed to read credentials file {:?}", path))?;
+        let mut file: CredentialsFile = serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse credentials file {:?}", path))?;
+        for credential in file.credentials.iter_mut() {
+            credential.token_sha256.make_ascii_lowercase();
+        }
+
+        info!(
+            credential_count = file.credentials.len(),
+            "Loaded game server credentials"
+        );
+        *self.credentials.write().unwrap() = file.credentials;
+        *self.modified.write().unwrap() = Some(modified);
+        Ok(())
+    }
+
+    /// Checks `token` grants every scope in `scopes` on `org_id` and returns the credential name
+    pub fn authorize(
+        &self,
+        token: Option<&str>,
+        org_id: &str,
+        scopes: &[Scope],
+    ) -> Result<String, AuthError> {
+        let token = token.ok_or(AuthError::MissingToken)?;
+        let token_sha256 = hash_token(token);
+        let credentials = self.credentials.read().unwrap();
+        // Compare against every credential in constant time so response timing does not
+        // reveal how much of a guessed token's hash matched
+        let credential = credentials
+            .iter()
+            .fold(None, |found, credential| {
+                let is_match: bool = credential
+                    .token_sha256
+                    .as_bytes()
+                    .ct_eq(token_sha256.as_bytes())
+                    .into();
+                match is_match {
+                    true => Some(credential),
+                    false => found,
+                }
+            })
+            .ok_or(AuthError::InvalidToken)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if credential
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now)
+        {
+            return Err(AuthError::ExpiredToken);
+        }
+
+        if !credential
+            .orgs
+            .iter()
+            .any(|allowed| allowed == ANY_ORG || allowed == org_id)
+        {
+            return Err(AuthError::OrgNotAllowed);
+        }
+
+        if let Some(missing) = scopes
+            .iter()
+            .find(|scope| !credential.scopes.contains(scope))
+        {
+            return Err(AuthError::ScopeNotAllowed(*missing));
+        }
+
+        Ok(credential.name.clone())
+    }
+}
+
+/// Polls the credentials file and swaps in its contents when it changes, so tokens can be
+/// added, rotated or revoked without restarting the relay
+#[instrument(skip(state))]
+pub async fn reload_credentials_task(state: SharedState, interval: Duration) {
+    loop {
+        sleep(interval).await;
+        if let Err(err) = state.auth.credentials.reload() {
+            error!(error = ?err, "Failed to reload credentials, keeping the previous ones");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(orgs: &[&str], scopes: &[Scope], expires_at: Option<u64>) -> CredentialStore {
+        CredentialStore {
+            path: None,
+            credentials: RwLock::new(vec![Credential {
+                name: "test".into(),
+                token_sha256: hash_token("secret"),
+                orgs: orgs.iter().map(|org| org.to_string()).collect(),
+                scopes: scopes.to_vec(),
+                expires_at,
+            }]),
+            modified: RwLock::new(None),
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn single_token_allows_every_org() {
+        let store = CredentialStore::from_token("secret");
+        let scopes = [Scope::Publish, Scope::Claim];
+        assert_eq!(
+            store.authorize(Some("secret"), "any", &scopes),
+            Ok("AUTH_TOKEN".into())
+        );
+        assert_eq!(
+            store.authorize(Some("wrong"), "any", &scopes),
+            Err(AuthError::InvalidToken)
+        );
+        assert_eq!(
+            store.authorize(None, "any", &scopes),
+            Err(AuthError::MissingToken)
+        );
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let expired = store(&["*"], &[Scope::Publish], Some(now()));
+        assert_eq!(
+            expired.authorize(Some("secret"), "org", &[Scope::Publish]),
+            Err(AuthError::ExpiredToken)
+        );
+
+        let valid = store(&["*"], &[Scope::Publish], Some(now() + 60));
+        assert!(valid
+            .authorize(Some("secret"), "org", &[Scope::Publish])
+            .is_ok());
+    }
+
+    #[test]
+    fn limits_tokens_to_their_orgs() {
+        let store = store(&["a", "b"], &[Scope::Publish], None);
+        assert!(store
+            .authorize(Some("secret"), "b", &[Scope::Publish])
+            .is_ok());
+        assert_eq!(
+            store.authorize(Some("secret"), "c", &[Scope::Publish]),
+            Err(AuthError::OrgNotAllowed)
+        );
+    }
+
+    #[test]
+    fn requires_every_scope() {
+        let store = store(&["*"], &[Scope::Publish], None);
+        assert!(store
+            .authorize(Some("secret"), "org", &[Scope::Publish])
+            .is_ok());
+        assert_eq!(
+            store.authorize(Some("secret"), "org", &[Scope::Publish, Scope::Claim]),
+            Err(AuthError::ScopeNotAllowed(Scope::Claim))
+        );
+    }
+
+    #[test]
+    fn invalid_tokens_are_unauthorized_and_missing_access_forbidden() {
+        for err in [
+            AuthError::MissingToken,
+            AuthError::InvalidToken,
+            AuthError::ExpiredToken,
+        ] {
+            assert_eq!(err.status_code(), StatusCode::UNAUTHORIZED);
+        }
+        for err in [
+            AuthError::OrgNotAllowed,
+            AuthError::ScopeNotAllowed(Scope::Claim),
+        ] {
+            assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[test]
+    fn loads_file_with_uppercase_hashes() {
+        let path = std::env::temp_dir().join(format!("credentials-{}.json", std::process::id()));
+        let contents = serde_json::json!({
+            "credentials": [{
+                "name": "from-file",
+                "tokenSha256": hash_token("secret").to_uppercase(),
+                "orgs": ["org"],
+                "scopes": ["publish"],
+            }]
+        });
+        std::fs::write(&path, contents.to_string()).unwrap();
+        let store = CredentialStore::from_file(path.clone());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            store
+                .unwrap()
+                .authorize(Some("secret"), "org", &[Scope::Publish]),
+            Ok("from-file".into())
+        );
+    }
+}
@@ -4,7 +4,7 @@ use serde::{de::Visitor, Deserialize, Serialize, Serializer};
 
 use crate::data::hex;
 
-#[derive(Debug, Copy)]
+#[derive(Debug, Copy, PartialEq)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
@@ -59,17 +59,45 @@ impl Color {
         }
     }
 
-    pub fn increment(&mut self) {
-        if self.r < 255 {
-            self.r += 1;
-        } else if self.g < 255 {
-            self.g += 1;
-        } else if self.b < 255 {
-            self.b += 1;
+    /// Hue in degrees, saturation and value between 0 and 1
+    pub fn to_hsv(self) -> (f32, f32, f32) {
+        let (r, g, b) = (
+            self.r as f32 / 255.0,
+            self.g as f32 / 255.0,
+            self.b as f32 / 255.0,
+        );
+        let max = r.max(g).max(b);
+        let delta = max - r.min(g).min(b);
+        let hue = if delta == 0.0 {
+            0.0
+        } else if max == r {
+            60.0 * ((g - b) / delta).rem_euclid(6.0)
+        } else if max == g {
+            60.0 * ((b - r) / delta + 2.0)
         } else {
-            self.b = 0;
-            self.r = 128;
-            self.g = 0;
+            60.0 * ((r - g) / delta + 4.0)
+        };
+        let saturation = if max == 0.0 { 0.0 } else { delta / max };
+        (hue, saturation, max)
+    }
+
+    pub fn from_hsv(hue: f32, saturation: f32, value: f32) -> Self {
+        let chroma = value * saturation;
+        let sector = hue.rem_euclid(360.0) / 60.0;
+        let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+        let (r, g, b) = match sector as u8 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let channel = |c: f32| ((c + value - chroma) * 255.0).round() as u8;
+        Self {
+            r: channel(r),
+            g: channel(g),
+            b: channel(b),
         }
     }
 }
@@ -0,0 +1,44 @@
+use std::borrow::Cow;
+
+use axum::extract::ws::{CloseFrame, Message};
+
+/// Why the relay closed a connection, sent to the peer as the websocket close code
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectReason {
+    PongTimeout,
+    IdleTimeout,
+    ServerRestarting,
+    KickedByAdmin,
+    MessageTooBig,
+}
+
+impl DisconnectReason {
+    pub fn close_code(&self) -> u16 {
+        match self {
+            DisconnectReason::PongTimeout => 4000,
+            DisconnectReason::IdleTimeout => 4001,
+            DisconnectReason::KickedByAdmin => 4002,
+            // Service Restart, see RFC 6455 section 7.4
+            DisconnectReason::ServerRestarting => 1012,
+            // Message Too Big, see RFC 6455 section 7.4
+            DisconnectReason::MessageTooBig => 1009,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::PongTimeout => "pong timeout",
+            DisconnectReason::IdleTimeout => "idle timeout",
+            DisconnectReason::KickedByAdmin => "kicked by admin",
+            DisconnectReason::ServerRestarting => "server restarting",
+            DisconnectReason::MessageTooBig => "message too big",
+        }
+    }
+
+    pub fn close_message(&self) -> Message {
+        Message::Close(Some(CloseFrame {
+            code: self.close_code(),
+            reason: Cow::Borrowed(self.as_str()),
+        }))
+    }
+}
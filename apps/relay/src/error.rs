@@ -0,0 +1,261 @@
+use std::{fmt, time::Duration};
+
+use axum::{
+    extract::ws::Message,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use tokio::{sync::mpsc::error::SendError, task::JoinError};
+
+use crate::{
+    credentials::{AuthError, Scope},
+    limits::{InputLimit, LimitExceeded},
+    validation::InvalidUpdate,
+};
+
+/// Everything that can go wrong while relaying. Peers get the `code`, which is stable across
+/// releases, the message is for humans and may change.
+#[derive(Debug)]
+pub enum RelayError {
+    /// A game server message that is not a scene update
+    Parse(serde_json::Error),
+    /// A message the peer is not expected to send, viewers only receive
+    UnexpectedMessage,
+    /// A `tickEnd` without the `tickBegin` it ends
+    NoOpenTick,
+    /// A batch element at this index is a control message, which is never batched
+    ControlInBatch(usize),
+    Validation(InvalidUpdate),
+    /// The update is for an item another game server has claimed
+    NotItemOwner,
+    /// The game server claims items another game server already holds
+    ItemsClaimed,
+    /// The relay simulates every org itself and turns game servers away
+    SimulationOnly,
+    /// Impulses need the physics simulation, which is not running
+    PhysicsNotRunning,
+    Auth(AuthError),
+    OriginNotAllowed,
+    /// Too many failed attempts from the ip or for the org
+    LockedOut(Duration),
+    Limit(LimitExceeded),
+    InputLimit(InputLimit),
+    Transport(axum::Error),
+    /// The viewer's queue is closed because its socket task has exited
+    ClientGone,
+    Task(JoinError),
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorCategory {
+    Parse,
+    Validation,
+    Auth,
+    Limit,
+    Transport,
+}
+
+/// The `error` message sent to the game server or viewer that caused the error
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorMessage {
+    pub category: ErrorCategory,
+    pub code: &'static str,
+    pub message: String,
+    /// The item the rejected update was for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub item_id: Option<String>,
+}
+
+impl RelayError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            RelayError::Parse(_)
+            | RelayError::UnexpectedMessage
+            | RelayError::NoOpenTick
+            | RelayError::ControlInBatch(_) => ErrorCategory::Parse,
+            RelayError::Validation(_)
+            | RelayError::NotItemOwner
+            | RelayError::ItemsClaimed
+            | RelayError::SimulationOnly
+            | RelayError::PhysicsNotRunning => ErrorCategory::Validation,
+            RelayError::Auth(_) | RelayError::OriginNotAllowed => ErrorCategory::Auth,
+            RelayError::LockedOut(_) | RelayError::Limit(_) | RelayError::InputLimit(_) => {
+                ErrorCategory::Limit
+            }
+            RelayError::Transport(_) | RelayError::ClientGone | RelayError::Task(_) => {
+                ErrorCategory::Transport
+            }
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            RelayError::Parse(_) => "invalid_message",
+            RelayError::UnexpectedMessage => "unexpected_message",
+            RelayError::NoOpenTick => "no_open_tick",
+            RelayError::ControlInBatch(_) => "control_in_batch",
+            RelayError::Validation(invalid) => invalid.as_str(),
+            RelayError::NotItemOwner => "not_item_owner",
+            RelayError::ItemsClaimed => "items_claimed",
+            RelayError::SimulationOnly => "simulation_only",
+            RelayError::PhysicsNotRunning => "physics_not_running",
+            RelayError::Auth(AuthError::MissingToken) => "missing_token",
+            RelayError::Auth(AuthError::InvalidToken) => "invalid_token",
+            RelayError::Auth(AuthError::ExpiredToken) => "token_expired",
+            RelayError::Auth(AuthError::OrgNotAllowed) => "org_not_allowed",
+            RelayError::Auth(AuthError::ScopeNotAllowed(Scope::Publish)) => "missing_publish_scope",
+            RelayError::Auth(AuthError::ScopeNotAllowed(Scope::Claim)) => "missing_claim_scope",
+            RelayError::OriginNotAllowed => "origin_not_allowed",
+            RelayError::LockedOut(_) => "locked_out",
+            RelayError::Limit(LimitExceeded::Connections) => "connection_limit",
+            RelayError::Limit(LimitExceeded::Orgs) => "org_limit",
+            RelayError::Limit(LimitExceeded::OrgViewers) => "org_viewer_limit",
+            RelayError::InputLimit(limit) => limit.as_str(),
+            RelayError::Transport(_) => "websocket_error",
+            RelayError::ClientGone => "client_gone",
+            RelayError::Task(_) => "task_failed",
+        }
+    }
+
+    /// Status for errors that reject a request before the websocket upgrade
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            RelayError::Parse(_)
+            | RelayError::UnexpectedMessage
+            | RelayError::NoOpenTick
+            | RelayError::ControlInBatch(_)
+            | RelayError::Validation(_) => StatusCode::BAD_REQUEST,
+            RelayError::NotItemOwner
+            | RelayError::ItemsClaimed
+            | RelayError::SimulationOnly
+            | RelayError::PhysicsNotRunning => StatusCode::CONFLICT,
+            RelayError::Auth(err) => err.status_code(),
+            RelayError::OriginNotAllowed => StatusCode::FORBIDDEN,
+            RelayError::LockedOut(_) => StatusCode::TOO_MANY_REQUESTS,
+            RelayError::Limit(err) => err.status_code(),
+            RelayError::InputLimit(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            RelayError::Transport(_) | RelayError::ClientGone | RelayError::Task(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    pub fn to_message(&self, item_id: Option<String>) -> ErrorMessage {
+        ErrorMessage {
+            category: self.category(),
+            code: self.code(),
+            message: self.to_string(),
+            item_id,
+        }
+    }
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelayError::Parse(err) => write!(f, "message is not a scene update: {}", err),
+            RelayError::UnexpectedMessage => write!(f, "viewers do not send messages"),
+            RelayError::NoOpenTick => write!(f, "tickEnd without a tickBegin"),
+            RelayError::ControlInBatch(index) => write!(
+                f,
+                "batch element {} is a control message, control messages are sent on their own",
+                index
+            ),
+            RelayError::Validation(invalid) => write!(f, "{}", invalid.message()),
+            RelayError::NotItemOwner => write!(f, "item is owned by another game server"),
+            RelayError::ItemsClaimed => write!(f, "items are claimed by another game server"),
+            RelayError::SimulationOnly => write!(f, "the relay simulates this org itself"),
+            RelayError::PhysicsNotRunning => write!(f, "the physics simulation is not running"),
+            RelayError::Auth(err) => write!(f, "{}", err.as_str()),
+            RelayError::OriginNotAllowed => write!(f, "origin not allowed"),
+            RelayError::LockedOut(_) => write!(f, "too many failed attempts"),
+            RelayError::Limit(err) => write!(f, "{}", err.as_str()),
+            RelayError::InputLimit(InputLimit::FrameSize) => {
+                write!(f, "frame is larger than the relay accepts")
+            }
+            RelayError::InputLimit(InputLimit::UpdateRate) => {
+                write!(f, "updates are sent faster than the relay accepts")
+            }
+            RelayError::InputLimit(InputLimit::BatchItems) => {
+                write!(f, "too many distinct items in one batch")
+            }
+            RelayError::Transport(err) => write!(f, "websocket error: {}", err),
+            RelayError::ClientGone => write!(f, "viewer socket is closed"),
+            RelayError::Task(err) => write!(f, "socket task failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RelayError {}
+
+impl From<serde_json::Error> for RelayError {
+    fn from(err: serde_json::Error) -> Self {
+        RelayError::Parse(err)
+    }
+}
+
+impl From<InvalidUpdate> for RelayError {
+    fn from(invalid: InvalidUpdate) -> Self {
+        RelayError::Validation(invalid)
+    }
+}
+
+impl From<AuthError> for RelayError {
+    fn from(err: AuthError) -> Self {
+        RelayError::Auth(err)
+    }
+}
+
+impl From<LimitExceeded> for RelayError {
+    fn from(err: LimitExceeded) -> Self {
+        RelayError::Limit(err)
+    }
+}
+
+impl From<InputLimit> for RelayError {
+    fn from(limit: InputLimit) -> Self {
+        RelayError::InputLimit(limit)
+    }
+}
+
+impl From<axum::Error> for RelayError {
+    fn from(err: axum::Error) -> Self {
+        RelayError::Transport(err)
+    }
+}
+
+impl From<SendError<Message>> for RelayError {
+    fn from(_: SendError<Message>) -> Self {
+        RelayError::ClientGone
+    }
+}
+
+impl From<JoinError> for RelayError {
+    fn from(err: JoinError) -> Self {
+        RelayError::Task(err)
+    }
+}
+
+/// Rejections before the upgrade carry the same body as the `error` message sent on a socket
+impl IntoResponse for RelayError {
+    fn into_response(self) -> Response {
+        let retry_after = match &self {
+            RelayError::LockedOut(locked_for) => Some(locked_for.as_secs().max(1).to_string()),
+            _ => None,
+        };
+        let mut response = (self.status_code(), Json(self.to_message(None))).into_response();
+        if let Some(retry_after) = retry_after {
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                retry_after
+                    .parse()
+                    .expect("seconds are a valid header value"),
+            );
+        }
+        response
+    }
+}
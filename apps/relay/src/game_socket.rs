@@ -1,199 +1,890 @@
 use std::{
+    net::SocketAddr,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicUsize, Ordering},
         Arc,
     },
+    time::{Duration, SystemTime, UNIX_EPOCH},
     vec,
 };
 
 use crate::{
-    org::{self, Org},
-    scene::{self, create_test_scene, SceneUpdate},
-    util::ErrorFormatter,
-    SharedState,
+    client_socket::ClientMessage,
+    config::millis,
+    credentials::Scope,
+    data::color::Color,
+    disconnect::DisconnectReason,
+    error::{ErrorMessage, RelayError},
+    heartbeat::{Heartbeat, HeartbeatAction},
+    limits::{ConnectionPermit, InputLimit, InputLimiter},
+    metrics::Metrics,
+    org::{self, DisconnectPolicy, GameServer, ItemOwnership, Org},
+    scene::SceneUpdate,
+    shutdown,
+    simulation::{physics::Impulse, SimulationMode},
+    util, SharedState,
 };
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        Path, State,
+        ConnectInfo, Path, Query, State,
     },
-    http::{status, HeaderMap},
+    http::HeaderMap,
     response::IntoResponse,
-    Error,
 };
-use futures_util::future::select_all;
-use rand::Rng;
-use tokio::{select, sync::Mutex, time::sleep};
-use tracing::{error, info, instrument, trace};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::{Mutex, Notify},
+    time::{interval, sleep, sleep_until, Instant, MissedTickBehavior},
+};
+use tokio_tungstenite::tungstenite;
+use tracing::{error, info, instrument, trace, warn};
+
+const DEFAULT_MESSAGE_INTERVAL_MS: u64 = 25;
+const DEFAULT_SIMULATION_INTERVAL_MS: u64 = 25;
+const DEFAULT_TICK_TIMEOUT_MS: u64 = 250;
+
+static GAME_SERVER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+const GAME_ENDPOINT: &str = "/game/:org";
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThrottleConfig {
+    /// How often viewers get a tick with everything that changed since the previous one,
+    /// whatever rate game servers and the simulation send at
+    #[serde(rename = "message_interval_ms", deserialize_with = "millis")]
+    pub message_interval: Duration,
+    /// How often the simulation produces a frame
+    #[serde(rename = "simulation_interval_ms", deserialize_with = "millis")]
+    pub simulation_interval: Duration,
+    /// How long a tick opened with `tickBegin` is held back before its updates are broadcast
+    /// without the `tickEnd`
+    #[serde(rename = "tick_timeout_ms", deserialize_with = "millis")]
+    pub tick_timeout: Duration,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            message_interval: Duration::from_millis(DEFAULT_MESSAGE_INTERVAL_MS),
+            simulation_interval: Duration::from_millis(DEFAULT_SIMULATION_INTERVAL_MS),
+            tick_timeout: Duration::from_millis(DEFAULT_TICK_TIMEOUT_MS),
+        }
+    }
+}
+
+/// Query parameters a game server uses to claim the items it is responsible for,
+/// e.g. `/game/:org?items=0,1&on_disconnect=despawn` or `/game/:org?prefix=enemy-`.
+/// A reconnecting server passes `resume=<token>` to pick up its previous session, `ack=true`
+/// makes the relay answer every update tagged with a `seq` with an `ack` or `nack`.
+#[derive(Deserialize, Debug, Default)]
+pub struct GameServerParams {
+    items: Option<String>,
+    prefix: Option<String>,
+    #[serde(default)]
+    on_disconnect: DisconnectPolicy,
+    resume: Option<String>,
+    #[serde(default)]
+    ack: bool,
+}
+
+/// A scene update as a game server sends it, `seq` is chosen by the server and echoed back in
+/// its `ack` or `nack`. Spelled out instead of flattening `SceneUpdate` so serde can read it
+/// straight from the frame without buffering it first.
+#[derive(Deserialize, Debug)]
+struct IncomingUpdate {
+    seq: Option<u64>,
+    id: String,
+    rotation: Option<(f32, f32, f32)>,
+    position: Option<(f32, f32, f32)>,
+    color: Option<Color>,
+    #[serde(default)]
+    despawned: bool,
+    /// Only control frames have a type, an update never does
+    #[serde(rename = "type")]
+    kind: Option<IgnoredAny>,
+}
+
+impl IncomingUpdate {
+    fn into_update(self) -> (Option<u64>, SceneUpdate) {
+        let update = SceneUpdate {
+            id: self.id,
+            rotation: self.rotation,
+            position: self.position,
+            color: self.color,
+            despawned: self.despawned,
+            mesh_type: None,
+            scale: None,
+        };
+        (self.seq, update)
+    }
+}
+
+/// Messages a game server sends besides updates, e.g. `{"type":"tickBegin"}`
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Control {
+    /// Updates up to the next `tickEnd` are broadcast together
+    TickBegin,
+    TickEnd,
+    /// Pushes an item the physics simulation moves, e.g.
+    /// `{"type":"impulse","id":"0","linear":[0,5,0]}`
+    Impulse {
+        id: String,
+        #[serde(flatten)]
+        impulse: Impulse,
+    },
+}
+
+/// One text frame from a game server
+#[derive(Debug)]
+enum GameServerFrame {
+    Update(IncomingUpdate),
+    /// Updates queued together, they are always broadcast in the same batch. A batch holding a
+    /// control message is rejected as a whole
+    Batch(Vec<IncomingUpdate>),
+    Control(Control),
+}
+
+impl GameServerFrame {
+    /// Picks the shape from the first character instead of trying each one in turn. Objects are
+    /// read as an update first since those are by far the most common.
+    fn parse(text: &str) -> Result<Self, RelayError> {
+        if text.trim_start().starts_with('[') {
+            return match serde_json::from_str::<Vec<IncomingUpdate>>(text) {
+                Ok(updates) => match updates.iter().position(|update| update.kind.is_some()) {
+                    Some(index) => Err(RelayError::ControlInBatch(index)),
+                    None => Ok(GameServerFrame::Batch(updates)),
+                },
+                Err(err) => Err(control_in_batch(text)
+                    .map(RelayError::ControlInBatch)
+                    .unwrap_or(RelayError::Parse(err))),
+            };
+        }
+        match serde_json::from_str::<IncomingUpdate>(text) {
+            Ok(update) if update.kind.is_none() => Ok(GameServerFrame::Update(update)),
+            Ok(_) => Ok(serde_json::from_str(text).map(GameServerFrame::Control)?),
+            Err(err) => serde_json::from_str(text)
+                .map(GameServerFrame::Control)
+                .map_err(|_| RelayError::Parse(err)),
+        }
+    }
+}
+
+/// Finds the first element with a `type` in a batch that did not parse as updates, e.g. a
+/// `tickBegin` without an `id`. Only runs for frames that are rejected anyway
+fn control_in_batch(text: &str) -> Option<usize> {
+    serde_json::from_str::<Vec<serde_json::Value>>(text)
+        .ok()?
+        .iter()
+        .position(|element| element.get("type").is_some())
+}
+
+/// Updates between a game server's `tickBegin` and `tickEnd`, held back so viewers never see
+/// half of a tick
+#[derive(Debug)]
+struct OpenTick {
+    updates: Vec<SceneUpdate>,
+    deadline: Instant,
+}
+
+/// Opens a new tick and returns the previous one if it never ended, so it can be broadcast
+fn begin_tick(open_tick: &mut Option<OpenTick>, timeout: Duration) -> Option<OpenTick> {
+    open_tick.replace(OpenTick {
+        updates: vec![],
+        deadline: Instant::now() + timeout,
+    })
+}
 
-const MESSAGE_THROTTLE_MS: u64 = 25;
-const SIM_THROTTLE_MS: u64 = 25;
+fn end_tick(open_tick: &mut Option<OpenTick>) -> Result<OpenTick, RelayError> {
+    open_tick.take().ok_or(RelayError::NoOpenTick)
+}
+
+/// Takes the open tick once it ran past its deadline without a `tickEnd`
+fn expired_tick(open_tick: &mut Option<OpenTick>, now: Instant) -> Option<OpenTick> {
+    open_tick.take_if(|tick| tick.deadline <= now)
+}
+
+/// What happened to one update, `Err` carries the id of the rejected item
+type QueueResult = (Option<u64>, Result<(), (RelayError, String)>);
+
+/// Messages the relay sends to a game server
+#[derive(Serialize, Debug)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum GameServerMessage {
+    /// Sent once after the upgrade, `resume_token` can be passed as `resume` when reconnecting
+    Session {
+        server_id: usize,
+        resume_token: String,
+        resumed: bool,
+    },
+    /// Sent right before the relay closes the socket because it is shutting down
+    Restarting { reconnect_in_ms: u64 },
+    /// Input was dropped since the last report because the server went over one of its limits
+    InputLimited { limit: InputLimit, dropped: usize },
+    /// A message from the game server was rejected, `itemId` is set for rejected updates and
+    /// impulses
+    Error(ErrorMessage),
+    /// The update tagged with `seq` was queued for the next broadcast, only sent in ack mode
+    Ack { seq: u64 },
+    /// The update tagged with `seq` was dropped, only sent in ack mode
+    Nack { seq: u64, error: ErrorMessage },
+}
 
-#[instrument(skip(ws, state, headers))]
+#[instrument(skip(ws, state, params, headers))]
 pub async fn game_handler(
     ws: WebSocketUpgrade,
     Path(org_id): Path<String>,
+    Query(params): Query<GameServerParams>,
     State(state): State<SharedState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
 ) -> impl IntoResponse {
-    info!(org_id, ?headers, "Gameserver establising connection");
+    info!(org_id, "Gameserver establising connection");
+    let ip = util::client_ip(&headers, addr, state.auth.trust_forwarded_for);
+    if let Some(locked_for) = state.auth.lockout.ip_locked_for(ip) {
+        state
+            .auth
+            .lockout
+            .record_locked(GAME_ENDPOINT, ip, Some(&org_id), locked_for);
+        return RelayError::LockedOut(locked_for).into_response();
+    }
+
     let auth_header = headers
         .get("authorization")
         .and_then(|header| header.to_str().ok());
 
-    if auth_header.is_none() || state.auth_token != auth_header.unwrap() {
-        info!(
-            "Failed to connect auth header is {}",
-            if auth_header.is_none() {
-                "missing"
-            } else {
-                "invalid"
+    let ownership = ItemOwnership::new(params.items.as_deref(), params.prefix.clone());
+    let required_scopes = match ownership.is_empty() {
+        true => vec![Scope::Publish],
+        false => vec![Scope::Publish, Scope::Claim],
+    };
+    let credential_name =
+        match state
+            .auth
+            .credentials
+            .authorize(auth_header, &org_id, &required_scopes)
+        {
+            Ok(credential_name) => credential_name,
+            Err(err) => {
+                let lockout = &state.auth.lockout;
+                lockout.record_failure(GAME_ENDPOINT, ip, Some(&org_id), err);
+                if let Some(locked_for) = lockout.org_locked_for(&org_id) {
+                    lockout.record_locked(GAME_ENDPOINT, ip, Some(&org_id), locked_for);
+                    return RelayError::LockedOut(locked_for).into_response();
+                }
+                return RelayError::from(err).into_response();
             }
+        };
+    state.auth.lockout.record_success(ip, Some(&org_id));
+    info!(credential_name, "Game server authorized");
+
+    if state
+        .simulation
+        .as_ref()
+        .is_some_and(|simulation| simulation.mode == SimulationMode::Exclusive)
+    {
+        info!("Game server rejected, the relay simulates every org");
+        return RelayError::SimulationOnly.into_response();
+    }
+
+    let connection_permit = match state.limits.admit(&*state.orgs.lock().await, &org_id) {
+        Ok(permit) => permit,
+        Err(err) => {
+            warn!(
+                limit = err.as_str(),
+                "Game server rejected by connection limits"
+            );
+            return RelayError::from(err).into_response();
+        }
+    };
+
+    let conflicting_server_id = state.orgs.lock().await.get_mut(&org_id).and_then(|org| {
+        let is_resuming = params
+            .resume
+            .as_deref()
+            .and_then(|token| org.resumable_server(token))
+            .is_some();
+        match is_resuming {
+            true => None,
+            false => org.conflicting_server(&ownership),
+        }
+    });
+    if let Some(conflicting_server_id) = conflicting_server_id {
+        info!(
+            conflicting_server_id,
+            "Failed to connect game server claims items owned by another server"
         );
-        return status::StatusCode::UNAUTHORIZED.into_response();
+        return RelayError::ItemsClaimed.into_response();
     }
 
-    ws.on_upgrade(|socket| handle_game_socket(socket, org_id, state))
+    let max_frame_bytes = state.limits.game_server().max_frame_bytes;
+    ws.max_message_size(max_frame_bytes)
+        .max_frame_size(max_frame_bytes)
+        .on_upgrade(move |socket| {
+            handle_game_socket(socket, org_id, state, ownership, params, connection_permit)
+        })
 }
 
-#[instrument(skip(socket, state))]
-async fn handle_game_socket(socket: WebSocket, org_id: String, state: SharedState) {
-    // TODO reject new connections
+#[instrument(skip(socket, state, params, _connection_permit))]
+async fn handle_game_socket(
+    mut socket: WebSocket,
+    org_id: String,
+    state: SharedState,
+    ownership: ItemOwnership,
+    params: GameServerParams,
+    _connection_permit: ConnectionPermit,
+) {
+    let resume_token = params.resume;
+    let stored_scene = org::load_stored_scene(&org_id, &state).await;
     let mut orgs = state.orgs.lock().await;
-    let org = orgs
-        .entry(org_id.clone())
-        .or_insert_with(|| org::Org::new(vec![], true, org_id.clone()));
+    let org = org::join_org(&mut orgs, &org_id, stored_scene, &state);
+
+    let resumed_server = resume_token
+        .as_deref()
+        .and_then(|token| org.resumable_server(token));
+    let (server_id, resumed) = match resumed_server {
+        Some(game_server) if game_server.is_detached() => {
+            game_server.reattach();
+            info!(
+                server_id = game_server.server_id,
+                "Game server resumed its session"
+            );
+            (game_server.server_id, true)
+        }
+        Some(game_server) => {
+            info!(
+                server_id = game_server.server_id,
+                "Game server tried to resume a session that is still connected, disconnecting"
+            );
+            return;
+        }
+        None => {
+            let server_id = GAME_SERVER_COUNT.fetch_add(1, Ordering::Relaxed);
+            if resume_token.is_some() {
+                info!(
+                    server_id,
+                    "Resume token unknown or expired, starting a new session"
+                );
+            }
+
+            // Another server may have claimed the same items between the handshake and the upgrade
+            if let Some(conflicting_server_id) = org.conflicting_server(&ownership) {
+                info!(
+                    server_id,
+                    conflicting_server_id,
+                    "Game server claims items owned by another server, disconnecting"
+                );
+                org::schedule_reap_if_idle(org, &state);
+                return;
+            }
+
+            org.game_servers
+                .push(GameServer::new(server_id, ownership, params.on_disconnect));
+            (server_id, false)
+        }
+    };
+    let game_server = org
+        .game_server_mut(server_id)
+        .expect("game server was registered above");
+    let session = GameServerMessage::Session {
+        server_id,
+        resume_token: game_server.resume_token.clone(),
+        resumed,
+    };
+    let pending_messages = game_server.pending_messages.clone();
+    let disconnect = game_server.disconnect.clone();
 
     info!(
+        server_id,
         client_count = org.clients.len(),
+        game_server_count = org.game_servers.len(),
         "New game server connected"
     );
-    let is_simulation = state.simulation;
     drop(orgs);
 
-    let pending_messages: Arc<Mutex<Vec<SceneUpdate>>> = Arc::new(Mutex::new(vec::Vec::new()));
-
-    let send_updates_task = tokio::spawn(send_message_task(
-        org_id.clone(),
-        state.clone(),
-        pending_messages.clone(),
-    ));
+    let session_message = serde_json::to_string(&session).expect("Failed to serialize message");
+    if let Err(err) = socket.send(Message::Text(session_message)).await {
+        let err = RelayError::from(err);
+        error!(
+            server_id,
+            code = err.code(),
+            error = %err,
+            "Error sending session to game server"
+        );
+    }
 
     let recv_messages_task = tokio::spawn(recv_messages_task(
         socket,
         org_id.clone(),
+        server_id,
+        state.clone(),
         pending_messages,
-        is_simulation,
+        disconnect,
+        params.ack,
     ));
 
-    let remaining_tasks = match select_all(vec![send_updates_task, recv_messages_task]).await {
-        (Ok(_), _, remaining) => remaining,
-        (Err(err), index, remaining) => {
-            error!(
-                index,
-                error = ErrorFormatter::format_join_error(err),
-                "Error in gamerserver handling task"
-            );
-            remaining
-        }
+    if let Err(err) = recv_messages_task.await {
+        let err = RelayError::from(err);
+        error!(
+            code = err.code(),
+            error = %err,
+            "Error in gamerserver handling task"
+        );
+    }
+
+    detach_game_server(org_id, server_id, state).await;
+}
+
+/// Keeps a disconnected game server's session, including its item ownership, around for the
+/// resume timeout before releasing it. Viewers keep seeing the server as connected meanwhile.
+#[instrument(skip(state))]
+async fn detach_game_server(org_id: String, server_id: usize, state: SharedState) {
+    let mut current_orgs = state.orgs.lock().await;
+    let Some(game_server) = current_orgs
+        .get_mut(&org_id)
+        .and_then(|org| org.game_server_mut(server_id))
+    else {
+        return;
     };
 
-    for task in remaining_tasks {
-        task.abort();
+    info!(
+        server_id,
+        resume_timeout_ms = state.lifecycle.resume_timeout.as_millis() as u64,
+        "Game server disconnected, waiting for it to resume"
+    );
+    let state_for_release_task = state.clone();
+    let release_task = tokio::spawn(async move {
+        sleep(state_for_release_task.lifecycle.resume_timeout).await;
+        info!(server_id, "Game server did not resume in time");
+        release_game_server(&org_id, server_id, state_for_release_task).await;
+    });
+    game_server.release_task = Some(release_task.abort_handle());
+}
+
+/// Unregisters a game server from its org, despawning the items it owned if it asked for that
+#[instrument(skip(state))]
+pub async fn release_game_server(org_id: &String, server_id: usize, state: SharedState) {
+    let mut current_orgs = state.orgs.lock().await;
+    let Some(org) = current_orgs.get_mut(org_id) else {
+        return;
+    };
+    let Some(game_server) = org.remove_game_server(server_id) else {
+        return;
+    };
+    info!(
+        server_id,
+        game_server_count = org.game_servers.len(),
+        "Game server detached from org"
+    );
+    org::schedule_reap_if_idle(org, &state);
+
+    if game_server.on_disconnect == DisconnectPolicy::Release {
+        info!(server_id, "Released items owned by game server");
+        return;
     }
+
+    let despawned = game_server
+        .ownership
+        .owned_items(&org.scene)
+        .into_iter()
+        .map(SceneUpdate::despawn)
+        .collect::<Vec<SceneUpdate>>();
+    if despawned.is_empty() {
+        return;
+    }
+
+    info!(
+        server_id,
+        despawned_count = despawned.len(),
+        "Despawning items owned by game server"
+    );
+    org.broadcast(despawned);
 }
 
-#[instrument(skip(state, pending_messages))]
-async fn send_message_task(
-    org_id: String,
-    state: SharedState,
-    pending_messages: Arc<Mutex<Vec<SceneUpdate>>>,
-) {
+/// Sends every org its next tick at the message interval, so viewers get a steady cadence
+/// however fast game servers send
+#[instrument(skip(state))]
+pub async fn broadcast_task(state: SharedState) {
+    let mut ticker = interval(state.throttle.message_interval);
+    // Late ticks are dropped rather than sent in a burst, viewers go by the server time anyway
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
     loop {
-        sleep(std::time::Duration::from_millis(MESSAGE_THROTTLE_MS)).await;
-        let messages = &pending_messages
-            .lock()
-            .await
-            .drain(..)
-            .fold(Vec::new(), |mut acc: Vec<SceneUpdate>, incoming_update| {
-                if acc.iter().any(|x| x.id == incoming_update.id) {
-                    acc.into_iter()
-                        .map(|mut current_update| {
-                            if current_update.id == incoming_update.id {
-                                current_update.position =
-                                    incoming_update.position.or(current_update.position);
-                                current_update.rotation =
-                                    incoming_update.rotation.or(current_update.rotation);
-                                current_update.color =
-                                    incoming_update.color.or(current_update.color);
-                            }
-                            current_update
-                        })
-                        .collect()
-                } else {
-                    acc.push(incoming_update);
-                    acc
-                }
-            })
-            .iter()
-            .map(|x| serde_json::to_string(x).expect("Failed to serialize message"))
-            .collect::<Vec<String>>()
-            .join(",");
+        ticker.tick().await;
+        let mut current_orgs = state.orgs.lock().await;
+        for org in current_orgs.values_mut() {
+            queue_pending_updates(org).await;
+            send_tick(org, &state.metrics).await;
+        }
+    }
+}
 
-        if messages.is_empty() {
-            continue;
+/// Queues what the org's game servers sent since the previous tick
+pub async fn queue_pending_updates(org: &mut Org) {
+    let pending = org
+        .game_servers
+        .iter()
+        .map(|game_server| game_server.pending_messages.clone())
+        .collect::<Vec<_>>();
+    for pending_messages in pending {
+        let updates = merge_updates(pending_messages.lock().await.drain(..));
+        if !updates.is_empty() {
+            org.broadcast(updates);
         }
+    }
+}
 
-        let message_to_send = Message::Text(format!("[{}]", messages));
-        let current_orgs = &mut state.orgs.lock().await;
-        let org = current_orgs.get_mut(&org_id);
-        if org.is_none() {
-            info!("Org not found cannot send message exiting");
-            return;
+/// Folds updates for the same item into one, later fields win
+pub fn merge_updates(updates: impl Iterator<Item = SceneUpdate>) -> Vec<SceneUpdate> {
+    updates.fold(Vec::new(), |mut acc: Vec<SceneUpdate>, incoming_update| {
+        if acc.iter().any(|x| x.id == incoming_update.id) {
+            acc.into_iter()
+                .map(|mut current_update| {
+                    if current_update.id == incoming_update.id {
+                        current_update.position =
+                            incoming_update.position.or(current_update.position);
+                        current_update.rotation =
+                            incoming_update.rotation.or(current_update.rotation);
+                        current_update.color = incoming_update.color.or(current_update.color);
+                        current_update.despawned = incoming_update.despawned;
+                        current_update.mesh_type = incoming_update
+                            .mesh_type
+                            .clone()
+                            .or(current_update.mesh_type);
+                        current_update.scale = incoming_update.scale.or(current_update.scale);
+                    }
+                    current_update
+                })
+                .collect()
+        } else {
+            acc.push(incoming_update);
+            acc
         }
-        send_message_to_client(org.unwrap(), message_to_send).await
+    })
+}
+
+/// Sends the org's next tick to its viewers with the server time, empty ticks included
+pub async fn send_tick(org: &mut Org, metrics: &Metrics) {
+    let started_at = std::time::Instant::now();
+    let (tick, updates) = org.next_tick();
+    if org.clients.is_empty() {
+        return;
+    }
+
+    let batch_size = updates.len();
+    let server_time_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let message = serde_json::to_string(&ClientMessage::Tick {
+        tick,
+        server_time_ms,
+        updates,
+    })
+    .expect("Failed to serialize message");
+    send_message_to_client(org, Message::Text(message), metrics).await;
+
+    if batch_size > 0 {
+        metrics.batches_sent.inc();
+        metrics.batch_size.observe(batch_size as f64);
+        metrics
+            .broadcast_latency
+            .observe(started_at.elapsed().as_secs_f64());
     }
 }
 
-#[instrument(skip(socket, pending_messages))]
+#[instrument(skip(socket, state, pending_messages, disconnect))]
 async fn recv_messages_task(
     mut socket: WebSocket,
     org_id: String,
+    server_id: usize,
+    state: SharedState,
     pending_messages: Arc<Mutex<Vec<SceneUpdate>>>,
-    is_simulation: bool,
+    disconnect: Arc<Notify>,
+    ack: bool,
 ) {
-    let mut scene = create_test_scene();
+    let mut heartbeat = Heartbeat::new(state.game_server_heartbeat);
+    let mut shutdown = state.shutdown.subscribe();
+    let mut input_limiter = InputLimiter::new(state.limits.game_server());
+    let mut open_tick: Option<OpenTick> = None;
     loop {
-        let msg = match is_simulation {
-            true => {
-                select! {
-                    r = recv_simulation_frame(&mut scene) => r,
-                    r = socket.recv() => r
+        let tick_deadline = open_tick.as_ref().map(|tick| tick.deadline);
+        let msg = select! {
+            _ = disconnect.notified() => {
+                info!(server_id, "Game server disconnected by admin");
+                if let Err(err) = socket.send(DisconnectReason::KickedByAdmin.close_message()).await {
+                    let err = RelayError::from(err);
+                    error!(
+                        code = err.code(),
+                        error = %err,
+                        "Error sending close frame to gameserver"
+                    );
+                }
+                return;
+            }
+            _ = shutdown::wait_for_shutdown(&mut shutdown) => {
+                info!(server_id, "Relay shutting down, disconnecting game server");
+                // The drain flushes pending updates once every game server is gone, an
+                // unfinished tick goes out with them like one that timed out
+                if let Some(tick) = open_tick.take() {
+                    close_tick(tick, &pending_messages).await;
+                }
+                let restarting = GameServerMessage::Restarting {
+                    reconnect_in_ms: state.lifecycle.reconnect_delay.as_millis() as u64,
+                };
+                let restarting = serde_json::to_string(&restarting).expect("Failed to serialize message");
+                for message in [Message::Text(restarting), DisconnectReason::ServerRestarting.close_message()] {
+                    if let Err(err) = socket.send(message).await {
+                        let err = RelayError::from(err);
+                        error!(
+                            code = err.code(),
+                            error = %err,
+                            "Error sending shutdown notice to gameserver"
+                        );
+                        break;
+                    }
+                }
+                return;
+            }
+            _ = sleep_until(tick_deadline.unwrap_or_else(Instant::now)), if tick_deadline.is_some() => {
+                if let Some(tick) = expired_tick(&mut open_tick, Instant::now()) {
+                    warn!(
+                        server_id,
+                        update_count = tick.updates.len(),
+                        "Tick did not end in time, broadcasting what it has"
+                    );
+                    close_tick(tick, &pending_messages).await;
+                }
+                continue;
+            }
+            r = socket.recv() => {
+                if let Some(Ok(msg)) = &r {
+                    heartbeat.received(msg);
+                }
+                r
+            }
+            action = heartbeat.next_action() => {
+                let message = match action {
+                    HeartbeatAction::SendPing => Message::Ping(vec![]),
+                    HeartbeatAction::Disconnect(reason) => {
+                        warn!(server_id, reason = reason.as_str(), "Disconnecting game server");
+                        if let Err(err) = socket.send(reason.close_message()).await {
+                            let err = RelayError::from(err);
+                            error!(
+                                code = err.code(),
+                                error = %err,
+                                "Error sending close frame to gameserver"
+                            );
+                        }
+                        return;
+                    }
+                };
+                if let Err(err) = socket.send(message).await {
+                    let err = RelayError::from(err);
+                    error!(
+                        code = err.code(),
+                        error = %err,
+                        "Error sending ping to gameserver, disconnecting"
+                    );
+                    return;
                 }
+                continue;
             }
-            false => socket.recv().await,
         };
 
         trace!(org_id, "Received message from gameserver");
         match msg {
-            Some(Ok(Message::Text(text))) => match serde_json::from_str::<SceneUpdate>(&text) {
-                Ok(parsed_update) => pending_messages.lock().await.push(parsed_update),
-                Err(err) => {
-                    error!(
-                        error = ErrorFormatter::format_serde_error(err),
-                        "Error parsing message from gameserver"
+            Some(Ok(Message::Text(text))) => {
+                state.metrics.messages_received.inc();
+                let (updates, rejected) = match GameServerFrame::parse(&text) {
+                    Ok(frame) => {
+                        state.metrics.messages_parsed.inc();
+                        match frame {
+                            GameServerFrame::Update(update) => (vec![update], None),
+                            GameServerFrame::Batch(updates) => (updates, None),
+                            GameServerFrame::Control(Control::TickBegin) => {
+                                let unfinished =
+                                    begin_tick(&mut open_tick, state.throttle.tick_timeout);
+                                if let Some(tick) = unfinished {
+                                    warn!(
+                                        server_id,
+                                        update_count = tick.updates.len(),
+                                        "Tick began before the previous one ended, broadcasting it"
+                                    );
+                                    close_tick(tick, &pending_messages).await;
+                                }
+                                (vec![], None)
+                            }
+                            GameServerFrame::Control(Control::TickEnd) => {
+                                match end_tick(&mut open_tick) {
+                                    Ok(tick) => {
+                                        close_tick(tick, &pending_messages).await;
+                                        (vec![], None)
+                                    }
+                                    Err(err) => (vec![], Some((err, None))),
+                                }
+                            }
+                            GameServerFrame::Control(Control::Impulse { id, impulse }) => {
+                                let pushed = match state.orgs.lock().await.get(&org_id) {
+                                    Some(org) if !org.can_update(server_id, &id) => {
+                                        Err(RelayError::NotItemOwner)
+                                    }
+                                    Some(org) => org.push_impulse(&id, impulse),
+                                    None => Err(RelayError::PhysicsNotRunning),
+                                };
+                                (vec![], pushed.err().map(|err| (err, Some(id))))
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        state.metrics.messages_failed.inc();
+                        (vec![], Some((err, None)))
+                    }
+                };
+
+                if let Some((err, item_id)) = rejected {
+                    warn!(
+                        server_id,
+                        item_id,
+                        code = err.code(),
+                        error = %err,
+                        "Rejected message from game server"
                     );
+                    let error = GameServerMessage::Error(err.to_message(item_id));
+                    if let Err(err) = send_game_server_message(&mut socket, &error).await {
+                        error!(
+                            code = err.code(),
+                            error = %err,
+                            "Error sending reply to gameserver, disconnecting"
+                        );
+                        return;
+                    }
                 }
-            },
+
+                let checked = match updates.is_empty() {
+                    true => vec![],
+                    false => check_updates(updates, &org_id, server_id, &state).await,
+                };
+                let results = match &mut open_tick {
+                    Some(tick) => {
+                        queue_updates(checked, &mut tick.updates, &state, &mut input_limiter)
+                    }
+                    None => queue_updates(
+                        checked,
+                        &mut *pending_messages.lock().await,
+                        &state,
+                        &mut input_limiter,
+                    ),
+                };
+
+                for (seq, queued) in results {
+                    let seq = seq.filter(|_| ack);
+                    let reply = match (queued, seq) {
+                        (Ok(()), Some(seq)) => Some(GameServerMessage::Ack { seq }),
+                        (Ok(()), None) => None,
+                        (Err((err, item_id)), seq) => {
+                            // Input over the limits is reported in bulk below
+                            let is_input_limit = matches!(err, RelayError::InputLimit(_));
+                            if !is_input_limit {
+                                warn!(
+                                    server_id,
+                                    seq,
+                                    item_id,
+                                    code = err.code(),
+                                    error = %err,
+                                    "Rejected update from game server"
+                                );
+                            }
+                            let error = err.to_message(Some(item_id));
+                            match seq {
+                                Some(seq) => Some(GameServerMessage::Nack { seq, error }),
+                                None if is_input_limit => None,
+                                None => Some(GameServerMessage::Error(error)),
+                            }
+                        }
+                    };
+                    if let Some(reply) = reply {
+                        if let Err(err) = send_game_server_message(&mut socket, &reply).await {
+                            error!(
+                                code = err.code(),
+                                error = %err,
+                                "Error sending reply to gameserver, disconnecting"
+                            );
+                            return;
+                        }
+                    }
+                }
+
+                // Drops are only reported while the server keeps sending, which is when it
+                // can act on them
+                for (limit, dropped) in input_limiter.take_report() {
+                    warn!(
+                        server_id,
+                        limit = limit.as_str(),
+                        dropped,
+                        "Dropped game server input over its limits"
+                    );
+                    let input_limited = GameServerMessage::InputLimited { limit, dropped };
+                    if let Err(err) = send_game_server_message(&mut socket, &input_limited).await {
+                        error!(
+                            code = err.code(),
+                            error = %err,
+                            "Error sending input limit report to gameserver, disconnecting"
+                        );
+                        return;
+                    }
+                }
+            }
 
             Some(Ok(Message::Close(_))) => {
                 info!("Game server disconnected");
                 return;
             }
             Some(Err(err)) => {
+                let capacity = std::error::Error::source(&err)
+                    .and_then(|source| source.downcast_ref::<tungstenite::Error>());
+                if let Some(tungstenite::Error::Capacity(capacity)) = capacity {
+                    let too_big = RelayError::InputLimit(InputLimit::FrameSize);
+                    warn!(
+                        server_id,
+                        code = too_big.code(),
+                        error = %capacity,
+                        "Game server frame over the size limit, disconnecting"
+                    );
+                    state
+                        .metrics
+                        .input_limited
+                        .with_label_values(&[InputLimit::FrameSize.as_str()])
+                        .inc();
+                    let too_big = GameServerMessage::Error(too_big.to_message(None));
+                    let close_message = DisconnectReason::MessageTooBig.close_message();
+                    if let Err(err) = send_game_server_message(&mut socket, &too_big).await {
+                        error!(
+                            code = err.code(),
+                            error = %err,
+                            "Error sending error to gameserver"
+                        );
+                    } else if let Err(err) = socket.send(close_message).await {
+                        let err = RelayError::from(err);
+                        error!(
+                            code = err.code(),
+                            error = %err,
+                            "Error sending close frame to gameserver"
+                        );
+                    }
+                    return;
+                }
+                let err = RelayError::from(err);
                 error!(
-                    error = ErrorFormatter::format_axum_error(err),
-                    "Error receiving message from simulation or gameserver, disconnecting"
+                    code = err.code(),
+                    error = %err,
+                    "Error receiving message from gameserver, disconnecting"
                 );
                 return;
             }
@@ -206,64 +897,284 @@ async fn recv_messages_task(
     }
 }
 
+/// Checks updates from one game server frame against the org, before they are queued
+async fn check_updates(
+    updates: Vec<IncomingUpdate>,
+    org_id: &str,
+    server_id: usize,
+    state: &SharedState,
+) -> Vec<(Option<u64>, SceneUpdate, Result<(), RelayError>)> {
+    let orgs = state.orgs.lock().await;
+    let org = orgs.get(org_id);
+    updates
+        .into_iter()
+        .map(|incoming| {
+            let (seq, update) = incoming.into_update();
+            let checked = match org {
+                Some(org) if !org.can_update(server_id, &update.id) => {
+                    Err(RelayError::NotItemOwner)
+                }
+                Some(org) => state
+                    .validation
+                    .validate(&update, &org.scene)
+                    .map_err(RelayError::from),
+                None => Ok(()),
+            };
+            (seq, update, checked)
+        })
+        .collect()
+}
+
+/// Adds the checked updates of one frame to `batch`, all of them at once so a frame is never
+/// split across two broadcasts
+fn queue_updates(
+    checked: Vec<(Option<u64>, SceneUpdate, Result<(), RelayError>)>,
+    batch: &mut Vec<SceneUpdate>,
+    state: &SharedState,
+    input_limiter: &mut InputLimiter,
+) -> Vec<QueueResult> {
+    checked
+        .into_iter()
+        .map(|(seq, update, checked)| {
+            let queued = checked.and_then(|()| {
+                input_limiter
+                    .check(&update, batch)
+                    .map_err(RelayError::from)
+            });
+            match queued {
+                Ok(()) => {
+                    batch.push(update);
+                    (seq, Ok(()))
+                }
+                Err(err) => {
+                    match &err {
+                        RelayError::Validation(invalid) => state
+                            .metrics
+                            .updates_rejected
+                            .with_label_values(&[invalid.as_str()])
+                            .inc(),
+                        RelayError::InputLimit(limit) => state
+                            .metrics
+                            .input_limited
+                            .with_label_values(&[limit.as_str()])
+                            .inc(),
+                        _ => {}
+                    }
+                    (seq, Err((err, update.id)))
+                }
+            }
+        })
+        .collect()
+}
+
+/// Hands a finished tick to the send task in one go
+async fn close_tick(tick: OpenTick, pending_messages: &Mutex<Vec<SceneUpdate>>) {
+    pending_messages.lock().await.extend(tick.updates);
+}
+
+async fn send_game_server_message(
+    socket: &mut WebSocket,
+    message: &GameServerMessage,
+) -> Result<(), RelayError> {
+    let message = serde_json::to_string(message).expect("Failed to serialize message");
+    Ok(socket.send(Message::Text(message)).await?)
+}
+
 #[instrument(skip(org, message))]
-async fn send_message_to_client(org: &mut Org, message: Message) {
+async fn send_message_to_client(org: &mut Org, message: Message, metrics: &Metrics) {
+    let message_len = match &message {
+        Message::Text(text) => text.len() as u64,
+        _ => 0,
+    };
     for client in org.clients.iter() {
-        if let Err(err) = client.tx.send(message.clone()) {
-            error!(
-                client_id = client.client_id,
-                error = ErrorFormatter::format_ws_send_error(err),
-                "Error producing message to client"
-            );
+        match client.send(message.clone()) {
+            Ok(()) => metrics.bytes_sent.inc_by(message_len),
+            Err(err) => {
+                metrics.send_errors.inc();
+                let err = RelayError::from(err);
+                error!(
+                    client_id = client.client_id,
+                    code = err.code(),
+                    error = %err,
+                    "Error producing message to client"
+                );
+            }
         }
     }
 }
 
-static DID_COLOR: AtomicBool = AtomicBool::new(false);
+#[cfg(test)]
+mod tests {
+    use crate::scene::test_update;
 
-#[instrument(skip(scene))]
-async fn recv_simulation_frame(scene: &mut scene::Scene) -> Option<Result<Message, Error>> {
-    sleep(std::time::Duration::from_millis(SIM_THROTTLE_MS)).await;
+    use super::*;
 
-    let item_index_to_update = {
-        let mut rng = rand::thread_rng();
-        rng.gen_range(0..scene.items.len())
-    };
-    let item_to_update = scene.items.get_mut(item_index_to_update);
+    #[test]
+    fn merges_updates_per_item_later_fields_win() {
+        let updates = vec![
+            SceneUpdate {
+                position: Some((1.0, 0.0, 0.0)),
+                rotation: Some((0.0, 1.0, 0.0)),
+                ..test_update("a")
+            },
+            SceneUpdate {
+                position: Some((2.0, 0.0, 0.0)),
+                ..test_update("b")
+            },
+            SceneUpdate {
+                position: Some((3.0, 0.0, 0.0)),
+                ..test_update("a")
+            },
+        ];
+        let merged = merge_updates(updates.into_iter());
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].id, "a");
+        assert_eq!(merged[0].position, Some((3.0, 0.0, 0.0)));
+        assert_eq!(merged[0].rotation, Some((0.0, 1.0, 0.0)));
+        assert_eq!(merged[1].id, "b");
+    }
 
-    if item_to_update.is_none() {
-        return Some(Err(Error::new("No items in scene")));
+    #[test]
+    fn merged_despawn_follows_last_update() {
+        let merged = merge_updates(
+            [
+                SceneUpdate {
+                    scale: Some((2.0, 2.0, 2.0)),
+                    ..test_update("a")
+                },
+                SceneUpdate::despawn("a".into()),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(merged.len(), 1);
+        assert!(merged[0].despawned);
+        assert_eq!(merged[0].scale, Some((2.0, 2.0, 2.0)));
+
+        let merged =
+            merge_updates([SceneUpdate::despawn("a".into()), test_update("a")].into_iter());
+        assert!(!merged[0].despawned);
     }
 
-    let item = item_to_update.unwrap();
-    let update_color = DID_COLOR
-        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |val| Some(!val))
-        .unwrap();
-    match update_color {
-        true => {
-            item.color.increment();
-            Some(Ok(Message::Text(
-                serde_json::to_string(&SceneUpdate {
-                    id: item_index_to_update.to_string(),
-                    color: Some(item.color),
-                    rotation: None,
-                    position: None,
-                })
-                .unwrap(),
-            )))
-        }
-        false => {
-            item.rotation.1 += 0.2;
-            item.rotation.2 += 0.2;
-            Some(Ok(Message::Text(
-                serde_json::to_string(&SceneUpdate {
-                    id: item_index_to_update.to_string(),
-                    rotation: Some((item.rotation.0, item.rotation.1, item.rotation.2)),
-                    color: None,
-                    position: None,
-                })
-                .unwrap(),
-            )))
+    #[test]
+    fn tick_ends_with_its_updates() {
+        let mut open_tick = None;
+        assert!(begin_tick(&mut open_tick, Duration::from_secs(60)).is_none());
+        open_tick.as_mut().unwrap().updates.push(test_update("a"));
+
+        let tick = end_tick(&mut open_tick).unwrap();
+        assert_eq!(tick.updates.len(), 1);
+        assert!(open_tick.is_none());
+    }
+
+    #[test]
+    fn tick_end_without_begin_is_rejected() {
+        assert!(matches!(end_tick(&mut None), Err(RelayError::NoOpenTick)));
+    }
+
+    #[test]
+    fn tick_begin_returns_unfinished_tick() {
+        let mut open_tick = None;
+        begin_tick(&mut open_tick, Duration::from_secs(60));
+        open_tick.as_mut().unwrap().updates.push(test_update("a"));
+
+        let unfinished = begin_tick(&mut open_tick, Duration::from_secs(60)).unwrap();
+        assert_eq!(unfinished.updates.len(), 1);
+        assert!(open_tick.as_ref().unwrap().updates.is_empty());
+    }
+
+    #[test]
+    fn tick_expires_at_deadline() {
+        let mut open_tick = None;
+        begin_tick(&mut open_tick, Duration::from_secs(60));
+        assert!(expired_tick(&mut open_tick, Instant::now()).is_none());
+        assert!(open_tick.is_some());
+
+        let deadline = open_tick.as_ref().unwrap().deadline;
+        assert!(expired_tick(&mut open_tick, deadline).is_some());
+        assert!(open_tick.is_none());
+    }
+
+    #[tokio::test]
+    async fn closed_tick_is_queued_after_pending_updates() {
+        let pending = Mutex::new(vec![test_update("a")]);
+        let tick = OpenTick {
+            updates: vec![test_update("b"), test_update("c")],
+            deadline: Instant::now(),
+        };
+        close_tick(tick, &pending).await;
+        let ids = pending
+            .lock()
+            .await
+            .iter()
+            .map(|update| update.id.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn parses_single_update() {
+        let frame = GameServerFrame::parse(r#"{"seq":3,"id":"a","position":[1,2,3]}"#).unwrap();
+        let GameServerFrame::Update(update) = frame else {
+            panic!("expected an update, got {:?}", frame);
+        };
+        let (seq, update) = update.into_update();
+        assert_eq!(seq, Some(3));
+        assert_eq!(update.id, "a");
+        assert_eq!(update.position, Some((1.0, 2.0, 3.0)));
+    }
+
+    #[test]
+    fn parses_batch() {
+        let frame = GameServerFrame::parse(r#" [{"id":"a"},{"id":"b","despawned":true}]"#).unwrap();
+        let GameServerFrame::Batch(updates) = frame else {
+            panic!("expected a batch, got {:?}", frame);
+        };
+        assert_eq!(updates.len(), 2);
+        assert!(updates[1].despawned);
+    }
+
+    #[test]
+    fn parses_control_messages() {
+        assert!(matches!(
+            GameServerFrame::parse(r#"{"type":"tickBegin"}"#),
+            Ok(GameServerFrame::Control(Control::TickBegin))
+        ));
+        assert!(matches!(
+            GameServerFrame::parse(r#"{"type":"tickEnd"}"#),
+            Ok(GameServerFrame::Control(Control::TickEnd))
+        ));
+        assert!(matches!(
+            GameServerFrame::parse(r#"{"type":"impulse","id":"a","linear":[0,5,0]}"#),
+            Ok(GameServerFrame::Control(Control::Impulse { id, .. })) if id == "a"
+        ));
+    }
+
+    #[test]
+    fn rejects_control_messages_in_batch() {
+        assert!(matches!(
+            GameServerFrame::parse(r#"[{"id":"a"},{"type":"tickBegin"}]"#),
+            Err(RelayError::ControlInBatch(1))
+        ));
+        assert!(matches!(
+            GameServerFrame::parse(r#"[{"type":"impulse","id":"a","linear":[0,5,0]}]"#),
+            Err(RelayError::ControlInBatch(0))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_frames() {
+        for text in [
+            "",
+            "not json",
+            r#"{"position":[1,2,3]}"#,
+            r#"{"type":"unknown"}"#,
+            r#"[{"id":"a"},{"position":[1,2,3]}]"#,
+        ] {
+            assert!(
+                matches!(GameServerFrame::parse(text), Err(RelayError::Parse(_))),
+                "{:?} should not parse",
+                text
+            );
         }
     }
 }
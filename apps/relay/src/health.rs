@@ -0,0 +1,62 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use tokio::time::timeout;
+use tracing::{instrument, warn};
+
+use crate::SharedState;
+
+/// How long readiness waits for the org registry lock or storage before reporting not ready
+const READINESS_CHECK_TIMEOUT_MS: u64 = 1_000;
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Readiness {
+    pub ready: bool,
+    /// `false` once a shutdown signal arrived, even while the listener is still open
+    pub accepting: bool,
+    /// Whether the org registry lock could be taken within the deadline
+    pub orgs_lock: bool,
+    /// `None` when no storage is configured
+    pub storage: Option<bool>,
+}
+
+/// Liveness, answers as long as the process can serve requests at all
+pub async fn get_healthz() -> &'static str {
+    "ok"
+}
+
+/// Readiness for load balancers, 503 while the relay cannot take new connections
+#[instrument(skip(state))]
+pub async fn get_readyz(State(state): State<SharedState>) -> (StatusCode, Json<Readiness>) {
+    let deadline = Duration::from_millis(READINESS_CHECK_TIMEOUT_MS);
+    let accepting = state.accepting.load(Ordering::Relaxed) && !*state.shutdown.borrow();
+    let orgs_lock = timeout(deadline, state.orgs.lock()).await.is_ok();
+    let storage = match &state.storage {
+        None => None,
+        Some(storage) => Some(match timeout(deadline, storage.check()).await {
+            Ok(Ok(())) => true,
+            Ok(Err(err)) => {
+                warn!(error = ?err, "Storage is not reachable");
+                false
+            }
+            Err(_) => {
+                warn!("Storage check timed out");
+                false
+            }
+        }),
+    };
+
+    let readiness = Readiness {
+        ready: accepting && orgs_lock && storage.unwrap_or(true),
+        accepting,
+        orgs_lock,
+        storage,
+    };
+    let status = match readiness.ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(readiness))
+}
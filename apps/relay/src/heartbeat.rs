@@ -0,0 +1,191 @@
+use std::time::Duration;
+
+use axum::extract::ws::Message;
+use serde::Deserialize;
+use tokio::time::{sleep_until, Instant};
+
+use crate::{config::millis, disconnect::DisconnectReason};
+
+const DEFAULT_PING_INTERVAL_MS: u64 = 15_000;
+const DEFAULT_PONG_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_IDLE_TIMEOUT_MS: u64 = 60_000;
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    /// How often a ping is sent to the peer
+    #[serde(rename = "ping_interval_ms", deserialize_with = "millis")]
+    pub ping_interval: Duration,
+    /// How long the peer has to answer a ping with a pong
+    #[serde(rename = "pong_timeout_ms", deserialize_with = "millis")]
+    pub pong_timeout: Duration,
+    /// How long the connection may go without receiving a text or binary frame, pongs and
+    /// other control frames do not count
+    #[serde(rename = "idle_timeout_ms", deserialize_with = "millis")]
+    pub idle_timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_millis(DEFAULT_PING_INTERVAL_MS),
+            pong_timeout: Duration::from_millis(DEFAULT_PONG_TIMEOUT_MS),
+            idle_timeout: Duration::from_millis(DEFAULT_IDLE_TIMEOUT_MS),
+        }
+    }
+}
+
+pub enum HeartbeatAction {
+    SendPing,
+    Disconnect(DisconnectReason),
+}
+
+/// Tracks pings and inbound traffic for one websocket, the owner of the socket
+/// awaits [`Heartbeat::next_action`] next to its reads and acts on the result
+#[derive(Debug)]
+pub struct Heartbeat {
+    config: HeartbeatConfig,
+    last_received: Instant,
+    next_ping: Instant,
+    ping_sent_at: Option<Instant>,
+}
+
+impl Heartbeat {
+    pub fn new(config: HeartbeatConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            config,
+            last_received: now,
+            next_ping: now + config.ping_interval,
+            ping_sent_at: None,
+        }
+    }
+
+    /// Records an inbound frame, only data frames keep the connection from going idle and a
+    /// pong satisfies the outstanding ping
+    pub fn received(&mut self, message: &Message) {
+        match message {
+            Message::Text(_) | Message::Binary(_) => self.last_received = Instant::now(),
+            Message::Pong(_) => self.ping_sent_at = None,
+            Message::Ping(_) | Message::Close(_) => {}
+        }
+    }
+
+    fn pong_deadline(&self) -> Option<Instant> {
+        self.ping_sent_at
+            .map(|ping_sent_at| ping_sent_at + self.config.pong_timeout)
+    }
+
+    fn idle_deadline(&self) -> Instant {
+        self.last_received + self.config.idle_timeout
+    }
+
+    /// Waits until a ping is due or one of the timeouts expires
+    pub async fn next_action(&mut self) -> HeartbeatAction {
+        let deadline = [Some(self.next_ping), self.pong_deadline()]
+            .into_iter()
+            .flatten()
+            .fold(self.idle_deadline(), Instant::min);
+        sleep_until(deadline).await;
+
+        let now = Instant::now();
+        if self.pong_deadline().is_some_and(|deadline| deadline <= now) {
+            return HeartbeatAction::Disconnect(DisconnectReason::PongTimeout);
+        }
+        if self.idle_deadline() <= now {
+            return HeartbeatAction::Disconnect(DisconnectReason::IdleTimeout);
+        }
+
+        self.next_ping = now + self.config.ping_interval;
+        self.ping_sent_at.get_or_insert(now);
+        HeartbeatAction::SendPing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeat() -> Heartbeat {
+        Heartbeat::new(HeartbeatConfig {
+            ping_interval: Duration::from_secs(15),
+            pong_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(60),
+        })
+    }
+
+    async fn next_action(heartbeat: &mut Heartbeat, start: Instant) -> (HeartbeatAction, u64) {
+        let action = heartbeat.next_action().await;
+        (action, start.elapsed().as_secs())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pings_at_interval_while_answered() {
+        let start = Instant::now();
+        let mut heartbeat = heartbeat();
+        for at in [15, 30, 45] {
+            let (action, elapsed) = next_action(&mut heartbeat, start).await;
+            assert!(matches!(action, HeartbeatAction::SendPing));
+            assert_eq!(elapsed, at);
+            heartbeat.received(&Message::Pong(vec![]));
+            heartbeat.received(&Message::Text("update".into()));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnects_without_pong() {
+        let start = Instant::now();
+        let mut heartbeat = heartbeat();
+        assert!(matches!(
+            next_action(&mut heartbeat, start).await,
+            (HeartbeatAction::SendPing, 15)
+        ));
+        // Traffic does not stand in for the pong
+        heartbeat.received(&Message::Text("update".into()));
+        assert!(matches!(
+            next_action(&mut heartbeat, start).await,
+            (
+                HeartbeatAction::Disconnect(DisconnectReason::PongTimeout),
+                25
+            )
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnects_when_only_control_frames_arrive() {
+        let start = Instant::now();
+        let mut heartbeat = heartbeat();
+        loop {
+            match next_action(&mut heartbeat, start).await {
+                (HeartbeatAction::SendPing, _) => {
+                    heartbeat.received(&Message::Pong(vec![]));
+                    heartbeat.received(&Message::Ping(vec![]));
+                }
+                (HeartbeatAction::Disconnect(reason), elapsed) => {
+                    assert_eq!(reason, DisconnectReason::IdleTimeout);
+                    assert_eq!(elapsed, 60);
+                    return;
+                }
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn data_frames_reset_idle_timeout() {
+        let start = Instant::now();
+        let mut heartbeat = heartbeat();
+        tokio::time::advance(Duration::from_secs(50)).await;
+        heartbeat.received(&Message::Binary(vec![1]));
+        heartbeat.received(&Message::Pong(vec![]));
+        loop {
+            match next_action(&mut heartbeat, start).await {
+                (HeartbeatAction::SendPing, _) => heartbeat.received(&Message::Pong(vec![])),
+                (HeartbeatAction::Disconnect(reason), elapsed) => {
+                    assert_eq!(reason, DisconnectReason::IdleTimeout);
+                    assert_eq!(elapsed, 110);
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,448 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{org::Org, scene::SceneUpdate};
+
+const DEFAULT_MAX_FRAME_BYTES: usize = 64 * 1024;
+const DEFAULT_UPDATES_PER_SECOND: u32 = 1_000;
+const DEFAULT_UPDATE_BURST: u32 = 2_000;
+const DEFAULT_MAX_ITEMS_PER_BATCH: usize = 1_000;
+/// Dropped input is reported to the game server at most this often
+const INPUT_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Viewers connected to one org at a time
+    pub max_viewers_per_org: Option<usize>,
+    /// Orgs held in memory, a connection that would create another one is rejected
+    pub max_orgs: Option<usize>,
+    /// Open viewer and game server sockets across every org, waiting viewers included
+    pub max_connections: Option<usize>,
+    /// Viewers over `max_viewers_per_org` wait for a free slot instead of being rejected
+    pub waiting_room: bool,
+    pub game_server: GameServerLimits,
+}
+
+/// Limits on what a single game server may send
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameServerLimits {
+    /// Larger frames close the socket with 1009, they are rejected before being buffered
+    pub max_frame_bytes: usize,
+    /// Sustained rate of scene updates, excess updates are dropped
+    pub updates_per_second: u32,
+    /// Updates that may arrive at once before the rate applies
+    pub update_burst: u32,
+    /// Distinct item ids in one batch, updates for further items are dropped until it is sent
+    pub max_items_per_batch: usize,
+}
+
+impl Default for GameServerLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            updates_per_second: DEFAULT_UPDATES_PER_SECOND,
+            update_burst: DEFAULT_UPDATE_BURST,
+            max_items_per_batch: DEFAULT_MAX_ITEMS_PER_BATCH,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitExceeded {
+    Connections,
+    Orgs,
+    OrgViewers,
+}
+
+impl LimitExceeded {
+    /// The relay being full is unavailability, one org being full is that org's quota
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            LimitExceeded::Connections | LimitExceeded::Orgs => StatusCode::SERVICE_UNAVAILABLE,
+            LimitExceeded::OrgViewers => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LimitExceeded::Connections => "relay is at its connection limit",
+            LimitExceeded::Orgs => "relay is at its org limit",
+            LimitExceeded::OrgViewers => "org is at its viewer limit",
+        }
+    }
+}
+
+/// Counts as one open connection until dropped, sockets hold it for their whole lifetime
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    connections: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+struct ViewerSlots {
+    semaphore: Arc<Semaphore>,
+    waiting: Arc<AtomicUsize>,
+}
+
+/// A viewer's place in an org, either connected or waiting for another viewer to leave
+#[derive(Debug)]
+pub enum ViewerSlot {
+    /// `None` when the org has no viewer limit
+    Admitted(Option<OwnedSemaphorePermit>),
+    Queued(QueuedViewer),
+}
+
+#[derive(Debug)]
+pub struct QueuedViewer {
+    semaphore: Arc<Semaphore>,
+    waiting: Arc<AtomicUsize>,
+    /// 1 for the next viewer to be admitted
+    pub position: usize,
+}
+
+impl QueuedViewer {
+    /// Waits for a free slot, viewers are admitted in the order they arrived
+    pub async fn admitted(self) -> OwnedSemaphorePermit {
+        self.semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("viewer slots are never closed")
+    }
+}
+
+impl Drop for QueuedViewer {
+    fn drop(&mut self) {
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionLimits {
+    config: LimitsConfig,
+    connections: Arc<AtomicUsize>,
+    viewer_slots: Mutex<HashMap<String, ViewerSlots>>,
+}
+
+impl ConnectionLimits {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            connections: Arc::new(AtomicUsize::new(0)),
+            viewer_slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn game_server(&self) -> GameServerLimits {
+        self.config.game_server
+    }
+
+    /// Checks the org and connection limits before a socket is upgraded
+    pub fn admit(
+        &self,
+        orgs: &HashMap<String, Org>,
+        org_id: &str,
+    ) -> Result<ConnectionPermit, LimitExceeded> {
+        if self
+            .config
+            .max_orgs
+            .is_some_and(|max_orgs| orgs.len() >= max_orgs && !orgs.contains_key(org_id))
+        {
+            return Err(LimitExceeded::Orgs);
+        }
+
+        let max_connections = self.config.max_connections.unwrap_or(usize::MAX);
+        self.connections
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |connections| {
+                (connections < max_connections).then_some(connections + 1)
+            })
+            .map_err(|_| LimitExceeded::Connections)?;
+        Ok(ConnectionPermit {
+            connections: self.connections.clone(),
+        })
+    }
+
+    /// Takes one of the org's viewer slots, or a place in its queue when the waiting room is on
+    pub fn viewer_slot(&self, org_id: &str) -> Result<ViewerSlot, LimitExceeded> {
+        let Some(max_viewers) = self.config.max_viewers_per_org else {
+            return Ok(ViewerSlot::Admitted(None));
+        };
+
+        let mut viewer_slots = self.viewer_slots.lock().unwrap();
+        let slots = viewer_slots
+            .entry(org_id.to_string())
+            .or_insert_with(|| ViewerSlots {
+                semaphore: Arc::new(Semaphore::new(max_viewers)),
+                waiting: Arc::new(AtomicUsize::new(0)),
+            });
+        // Viewers already waiting go first
+        if slots.waiting.load(Ordering::Relaxed) == 0 {
+            if let Ok(permit) = slots.semaphore.clone().try_acquire_owned() {
+                return Ok(ViewerSlot::Admitted(Some(permit)));
+            }
+        }
+        if !self.config.waiting_room {
+            return Err(LimitExceeded::OrgViewers);
+        }
+
+        let position = slots.waiting.fetch_add(1, Ordering::Relaxed) + 1;
+        Ok(ViewerSlot::Queued(QueuedViewer {
+            semaphore: slots.semaphore.clone(),
+            waiting: slots.waiting.clone(),
+            position,
+        }))
+    }
+
+    /// Drops the org's viewer slots once nobody holds or waits for one
+    pub fn forget_org(&self, org_id: &str) {
+        let mut viewer_slots = self.viewer_slots.lock().unwrap();
+        if viewer_slots.get(org_id).is_some_and(|slots| {
+            Arc::strong_count(&slots.semaphore) == 1 && slots.waiting.load(Ordering::Relaxed) == 0
+        }) {
+            viewer_slots.remove(org_id);
+        }
+    }
+}
+
+/// Which game server input limit dropped or rejected input
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum InputLimit {
+    FrameSize,
+    UpdateRate,
+    BatchItems,
+}
+
+impl InputLimit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InputLimit::FrameSize => "frame_size",
+            InputLimit::UpdateRate => "update_rate",
+            InputLimit::BatchItems => "batch_items",
+        }
+    }
+}
+
+/// Holds up to `capacity` tokens and refills `rate` tokens per second
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, capacity: u32) -> Self {
+        Self {
+            capacity: capacity as f64,
+            rate: rate as f64,
+            tokens: capacity as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = (now - self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Enforces [`GameServerLimits`] on one game server's updates and collects what it dropped
+#[derive(Debug)]
+pub struct InputLimiter {
+    limits: GameServerLimits,
+    bucket: TokenBucket,
+    dropped: HashMap<InputLimit, usize>,
+    last_report: Option<Instant>,
+}
+
+impl InputLimiter {
+    pub fn new(limits: GameServerLimits) -> Self {
+        Self {
+            limits,
+            bucket: TokenBucket::new(limits.updates_per_second, limits.update_burst),
+            dropped: HashMap::new(),
+            last_report: None,
+        }
+    }
+
+    /// Checks whether `update` may join `pending`, the batch waiting to be broadcast or the
+    /// open tick. The items are counted from `pending` itself, so updates added to it some
+    /// other way, like a closed tick, count as well
+    pub fn check(
+        &mut self,
+        update: &SceneUpdate,
+        pending: &[SceneUpdate],
+    ) -> Result<(), InputLimit> {
+        if !pending.iter().any(|queued| queued.id == update.id)
+            && batch_items(pending) >= self.limits.max_items_per_batch
+        {
+            return self.dropped(InputLimit::BatchItems);
+        }
+        if !self.bucket.try_take() {
+            return self.dropped(InputLimit::UpdateRate);
+        }
+        Ok(())
+    }
+
+    fn dropped(&mut self, limit: InputLimit) -> Result<(), InputLimit> {
+        *self.dropped.entry(limit).or_default() += 1;
+        Err(limit)
+    }
+
+    /// Drops to report since the last report, the first drop is reported straight away and
+    /// later ones at most once per interval
+    pub fn take_report(&mut self) -> Vec<(InputLimit, usize)> {
+        if self.dropped.is_empty()
+            || self
+                .last_report
+                .is_some_and(|last_report| last_report.elapsed() < INPUT_REPORT_INTERVAL)
+        {
+            return vec![];
+        }
+        self.last_report = Some(Instant::now());
+        self.dropped.drain().collect()
+    }
+}
+
+/// Number of distinct items in `batch`
+fn batch_items(batch: &[SceneUpdate]) -> usize {
+    batch
+        .iter()
+        .map(|update| update.id.as_str())
+        .collect::<HashSet<&str>>()
+        .len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(update_burst: u32, max_items_per_batch: usize) -> InputLimiter {
+        InputLimiter::new(GameServerLimits {
+            updates_per_second: 1,
+            update_burst,
+            max_items_per_batch,
+            ..GameServerLimits::default()
+        })
+    }
+
+    fn queue(
+        limiter: &mut InputLimiter,
+        pending: &mut Vec<SceneUpdate>,
+        id: &str,
+    ) -> Result<(), InputLimit> {
+        let update = SceneUpdate::despawn(id.into());
+        limiter.check(&update, pending)?;
+        pending.push(update);
+        Ok(())
+    }
+
+    #[test]
+    fn bucket_allows_burst_then_refills() {
+        let mut bucket = TokenBucket::new(2, 3);
+        assert!((0..3).all(|_| bucket.try_take()));
+        assert!(!bucket.try_take());
+
+        bucket.last_refill -= Duration::from_secs(1);
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn bucket_refill_is_capped() {
+        let mut bucket = TokenBucket::new(100, 2);
+        bucket.last_refill -= Duration::from_secs(60);
+        assert!((0..2).all(|_| bucket.try_take()));
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn limits_distinct_items_per_batch() {
+        let mut limiter = limiter(100, 2);
+        let mut pending = vec![];
+        assert_eq!(queue(&mut limiter, &mut pending, "a"), Ok(()));
+        assert_eq!(queue(&mut limiter, &mut pending, "b"), Ok(()));
+        assert_eq!(queue(&mut limiter, &mut pending, "a"), Ok(()));
+        assert_eq!(
+            queue(&mut limiter, &mut pending, "c"),
+            Err(InputLimit::BatchItems)
+        );
+
+        pending.clear();
+        assert_eq!(queue(&mut limiter, &mut pending, "c"), Ok(()));
+    }
+
+    #[test]
+    fn ticks_and_batches_share_the_item_limit() {
+        let mut limiter = limiter(100, 2);
+        let mut pending = vec![];
+        assert_eq!(queue(&mut limiter, &mut pending, "a"), Ok(()));
+
+        // A tick fills its own batch, then is closed into the pending one
+        let mut tick = vec![];
+        assert_eq!(queue(&mut limiter, &mut tick, "b"), Ok(()));
+        assert_eq!(queue(&mut limiter, &mut tick, "c"), Ok(()));
+        assert_eq!(
+            queue(&mut limiter, &mut tick, "d"),
+            Err(InputLimit::BatchItems)
+        );
+        pending.extend(tick);
+
+        assert_eq!(
+            queue(&mut limiter, &mut pending, "d"),
+            Err(InputLimit::BatchItems)
+        );
+        assert_eq!(queue(&mut limiter, &mut pending, "b"), Ok(()));
+
+        // A broadcast takes the batch, the next tick starts from nothing
+        pending.clear();
+        let mut tick = vec![];
+        assert_eq!(queue(&mut limiter, &mut tick, "d"), Ok(()));
+        assert_eq!(queue(&mut limiter, &mut tick, "e"), Ok(()));
+        assert_eq!(queue(&mut limiter, &mut pending, "f"), Ok(()));
+    }
+
+    #[test]
+    fn limits_update_rate_and_reports_drops() {
+        let mut limiter = limiter(2, 100);
+        let mut pending = vec![];
+        assert_eq!(queue(&mut limiter, &mut pending, "a"), Ok(()));
+        assert_eq!(queue(&mut limiter, &mut pending, "a"), Ok(()));
+        assert_eq!(
+            queue(&mut limiter, &mut pending, "a"),
+            Err(InputLimit::UpdateRate)
+        );
+        assert_eq!(limiter.take_report(), vec![(InputLimit::UpdateRate, 1)]);
+
+        let _ = queue(&mut limiter, &mut pending, "a");
+        assert_eq!(limiter.take_report(), vec![]);
+    }
+}
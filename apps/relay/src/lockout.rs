@@ -0,0 +1,374 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{config::millis, credentials::AuthError};
+
+/// Records older than this without a new failure are forgotten
+const FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+/// How often stale records are pruned from the table
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+const DEFAULT_IP_THRESHOLD: u32 = 5;
+const DEFAULT_ORG_THRESHOLD: u32 = 20;
+const DEFAULT_BASE_LOCKOUT_MS: u64 = 1_000;
+const DEFAULT_MAX_LOCKOUT_MS: u64 = 15 * 60 * 1_000;
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockoutConfig {
+    /// Failed attempts from one ip before it gets locked out
+    pub ip_threshold: u32,
+    /// Failed attempts against one org, from any ip, before the org gets locked out
+    pub org_threshold: u32,
+    /// Lockout after reaching a threshold, doubled with every further failure
+    #[serde(rename = "base_ms", deserialize_with = "millis")]
+    pub base_lockout: Duration,
+    #[serde(rename = "max_ms", deserialize_with = "millis")]
+    pub max_lockout: Duration,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            ip_threshold: DEFAULT_IP_THRESHOLD,
+            org_threshold: DEFAULT_ORG_THRESHOLD,
+            base_lockout: Duration::from_millis(DEFAULT_BASE_LOCKOUT_MS),
+            max_lockout: Duration::from_millis(DEFAULT_MAX_LOCKOUT_MS),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum LockoutKey {
+    Ip(IpAddr),
+    Org(String),
+}
+
+#[derive(Debug)]
+struct FailureRecord {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct FailureTable {
+    records: HashMap<LockoutKey, FailureRecord>,
+    last_prune: Instant,
+}
+
+impl FailureTable {
+    /// Forgets records whose window passed and whose lockout expired, at most once per
+    /// `PRUNE_INTERVAL` so a flood of failures does not scan the table every time
+    fn prune(&mut self, now: Instant) {
+        if now - self.last_prune < PRUNE_INTERVAL {
+            return;
+        }
+        self.last_prune = now;
+        self.records.retain(|_, record| {
+            now - record.last_failure < FAILURE_WINDOW
+                || record.locked_until.is_some_and(|until| until > now)
+        });
+    }
+
+    fn locked_for(&self, key: &LockoutKey, now: Instant) -> Option<Duration> {
+        self.records
+            .get(key)?
+            .locked_until
+            .filter(|locked_until| *locked_until > now)
+            .map(|locked_until| locked_until - now)
+    }
+}
+
+/// Counts failed authentication attempts per ip and per org and locks them out with an
+/// exponentially growing backoff, so tokens cannot be brute forced.
+///
+/// Callers check the ip lockout before authorizing but the org lockout only once
+/// authorization failed, so failures from other ips cannot lock valid credentials out of
+/// their own org.
+#[derive(Debug)]
+pub struct AuthLockout {
+    config: LockoutConfig,
+    failures: Mutex<FailureTable>,
+}
+
+impl AuthLockout {
+    pub fn new(config: LockoutConfig) -> Self {
+        Self {
+            config,
+            failures: Mutex::new(FailureTable {
+                records: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+        }
+    }
+
+    /// Returns how much longer `ip` is locked out for, if it is
+    pub fn ip_locked_for(&self, ip: IpAddr) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        failures.locked_for(&LockoutKey::Ip(ip), Instant::now())
+    }
+
+    /// Returns how much longer `org_id` is locked out for, if it is
+    pub fn org_locked_for(&self, org_id: &str) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        failures.locked_for(&LockoutKey::Org(org_id.to_string()), Instant::now())
+    }
+
+    /// Counts a failed attempt and emits an audit event for it, endpoints that do not belong
+    /// to an org like `/admin` pass `None` and only count against the ip
+    pub fn record_failure(
+        &self,
+        endpoint: &str,
+        ip: IpAddr,
+        org_id: Option<&str>,
+        reason: AuthError,
+    ) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        failures.prune(now);
+        let failures = &mut failures.records;
+
+        let ip_failures = self.bump(failures, LockoutKey::Ip(ip), self.config.ip_threshold);
+        let org_failures = org_id.map(|org_id| {
+            self.bump(
+                failures,
+                LockoutKey::Org(org_id.to_string()),
+                self.config.org_threshold,
+            )
+        });
+        let org_key = org_id.map(|org_id| LockoutKey::Org(org_id.to_string()));
+        let locked_for_ms = [Some(LockoutKey::Ip(ip)), org_key]
+            .iter()
+            .flatten()
+            .filter_map(|key| failures.get(key)?.locked_until)
+            .max()
+            .map(|locked_until| (locked_until - now).as_millis() as u64);
+
+        warn!(
+            target: "audit",
+            event = "auth_failure",
+            endpoint,
+            %ip,
+            org_id,
+            reason = reason.as_str(),
+            ip_failures,
+            org_failures,
+            locked_for_ms,
+            "Authentication failed"
+        );
+    }
+
+    /// Emits an audit event for an attempt rejected because of an active lockout
+    pub fn record_locked(
+        &self,
+        endpoint: &str,
+        ip: IpAddr,
+        org_id: Option<&str>,
+        locked_for: Duration,
+    ) {
+        warn!(
+            target: "audit",
+            event = "auth_locked_out",
+            endpoint,
+            %ip,
+            org_id,
+            locked_for_ms = locked_for.as_millis() as u64,
+            "Authentication attempt rejected while locked out"
+        );
+    }
+
+    /// A successful login clears the ip's failures and halves the org's counter, lifting its
+    /// lockout once the counter drops below the threshold again
+    pub fn record_success(&self, ip: IpAddr, org_id: Option<&str>) {
+        let mut failures = self.failures.lock().unwrap();
+        let failures = &mut failures.records;
+        failures.remove(&LockoutKey::Ip(ip));
+
+        let Some(org_id) = org_id else {
+            return;
+        };
+        let org_key = LockoutKey::Org(org_id.to_string());
+        if let Some(record) = failures.get_mut(&org_key) {
+            record.count /= 2;
+            if record.count == 0 {
+                failures.remove(&org_key);
+            } else if record.count < self.config.org_threshold {
+                record.locked_until = None;
+            }
+        }
+    }
+
+    fn bump(
+        &self,
+        failures: &mut HashMap<LockoutKey, FailureRecord>,
+        key: LockoutKey,
+        threshold: u32,
+    ) -> u32 {
+        let now = Instant::now();
+        let record = failures.entry(key).or_insert(FailureRecord {
+            count: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        if now - record.last_failure >= FAILURE_WINDOW {
+            record.count = 0;
+        }
+        record.count += 1;
+        record.last_failure = now;
+
+        if record.count >= threshold {
+            let exponent = (record.count - threshold).min(16);
+            let lockout = self
+                .config
+                .base_lockout
+                .saturating_mul(1 << exponent)
+                .min(self.config.max_lockout);
+            record.locked_until = Some(now + lockout);
+        }
+        record.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, thread};
+
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const OTHER_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    fn lockout(base_lockout: Duration) -> AuthLockout {
+        AuthLockout::new(LockoutConfig {
+            ip_threshold: 3,
+            org_threshold: 5,
+            base_lockout,
+            max_lockout: base_lockout * 4,
+        })
+    }
+
+    fn fail(lockout: &AuthLockout, ip: IpAddr, times: u32) {
+        for _ in 0..times {
+            lockout.record_failure("test", ip, Some("org"), AuthError::InvalidToken);
+        }
+    }
+
+    #[test]
+    fn locks_ip_at_threshold() {
+        let lockout = lockout(Duration::from_secs(60));
+        fail(&lockout, IP, 2);
+        assert_eq!(lockout.ip_locked_for(IP), None);
+
+        fail(&lockout, IP, 1);
+        assert!(lockout.ip_locked_for(IP).is_some());
+        assert_eq!(lockout.ip_locked_for(OTHER_IP), None);
+    }
+
+    #[test]
+    fn lockout_doubles_up_to_max() {
+        let lockout = lockout(Duration::from_secs(60));
+        let locked_until = |lockout: &AuthLockout| {
+            let failures = lockout.failures.lock().unwrap();
+            let record = &failures.records[&LockoutKey::Ip(IP)];
+            record.locked_until.unwrap() - record.last_failure
+        };
+
+        fail(&lockout, IP, 3);
+        assert_eq!(locked_until(&lockout), Duration::from_secs(60));
+        fail(&lockout, IP, 1);
+        assert_eq!(locked_until(&lockout), Duration::from_secs(120));
+        fail(&lockout, IP, 5);
+        assert_eq!(locked_until(&lockout), Duration::from_secs(240));
+    }
+
+    #[test]
+    fn lockout_expires() {
+        let lockout = lockout(Duration::from_millis(20));
+        fail(&lockout, IP, 3);
+        assert!(lockout.ip_locked_for(IP).is_some());
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(lockout.ip_locked_for(IP), None);
+    }
+
+    #[test]
+    fn count_resets_after_window() {
+        let lockout = lockout(Duration::from_secs(60));
+        fail(&lockout, IP, 2);
+        {
+            let mut failures = lockout.failures.lock().unwrap();
+            let record = failures.records.get_mut(&LockoutKey::Ip(IP)).unwrap();
+            record.last_failure -= FAILURE_WINDOW;
+        }
+
+        fail(&lockout, IP, 1);
+        assert_eq!(lockout.ip_locked_for(IP), None);
+    }
+
+    #[test]
+    fn org_locks_across_ips() {
+        let lockout = lockout(Duration::from_secs(60));
+        fail(&lockout, IP, 2);
+        fail(&lockout, OTHER_IP, 2);
+        assert_eq!(lockout.org_locked_for("org"), None);
+
+        fail(&lockout, OTHER_IP, 1);
+        assert!(lockout.org_locked_for("org").is_some());
+        assert_eq!(lockout.org_locked_for("other"), None);
+    }
+
+    #[test]
+    fn success_clears_ip_and_decays_org() {
+        let lockout = lockout(Duration::from_secs(60));
+        fail(&lockout, IP, 3);
+        fail(&lockout, OTHER_IP, 3);
+        assert!(lockout.org_locked_for("org").is_some());
+
+        lockout.record_success(IP, Some("org"));
+        assert_eq!(lockout.ip_locked_for(IP), None);
+        assert!(lockout.ip_locked_for(OTHER_IP).is_some());
+        assert_eq!(lockout.org_locked_for("org"), None);
+
+        let failures = lockout.failures.lock().unwrap();
+        assert_eq!(failures.records[&LockoutKey::Org("org".into())].count, 3);
+    }
+
+    #[test]
+    fn failures_without_org_only_count_against_ip() {
+        let lockout = lockout(Duration::from_secs(60));
+        for _ in 0..5 {
+            lockout.record_failure("test", IP, None, AuthError::InvalidToken);
+        }
+        assert!(lockout.ip_locked_for(IP).is_some());
+        assert_eq!(lockout.org_locked_for("org"), None);
+
+        lockout.record_success(IP, None);
+        assert_eq!(lockout.ip_locked_for(IP), None);
+    }
+
+    #[test]
+    fn prunes_stale_records() {
+        let lockout = lockout(Duration::from_millis(1));
+        fail(&lockout, IP, 1);
+        {
+            let mut failures = lockout.failures.lock().unwrap();
+            failures.last_prune -= PRUNE_INTERVAL;
+            for record in failures.records.values_mut() {
+                record.last_failure -= FAILURE_WINDOW;
+            }
+        }
+
+        fail(&lockout, OTHER_IP, 1);
+        let failures = lockout.failures.lock().unwrap();
+        assert!(!failures.records.contains_key(&LockoutKey::Ip(IP)));
+        assert!(failures.records.contains_key(&LockoutKey::Ip(OTHER_IP)));
+    }
+}
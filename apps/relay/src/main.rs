@@ -1,79 +1,215 @@
+mod admin;
 mod client_socket;
+mod config;
+mod credentials;
 mod data;
+mod disconnect;
+mod error;
 mod game_socket;
+mod health;
+mod heartbeat;
+mod limits;
+mod lockout;
+mod metrics;
 mod org;
+mod origin;
 mod scene;
+mod scripting;
+mod shutdown;
+mod simulation;
+mod storage;
+mod tls;
 mod util;
+mod validation;
+mod viewer_auth;
 
+use config::{Config, Environment};
+use credentials::CredentialStore;
+use heartbeat::HeartbeatConfig;
+use limits::ConnectionLimits;
+use lockout::AuthLockout;
+use metrics::Metrics;
 use org::Org;
-use scene::Scene;
-use std::str::FromStr;
-use std::{collections::HashMap, sync::Arc};
+use origin::OriginConfig;
+use scripting::ScriptConfig;
+use simulation::SimulationConfig;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use storage::SceneStorage;
 use tracing::{info, level_filters::LevelFilter};
-use tracing::{instrument, span};
+use tracing::{instrument, warn};
 
-use crate::{client_socket::client_handler, game_socket::game_handler, scene::get_scene};
+use crate::{
+    client_socket::client_handler,
+    config::millis,
+    game_socket::{game_handler, ThrottleConfig},
+    scene::get_scene,
+};
 use axum::{routing::get, Router};
+use serde::Deserialize;
 use tracing_subscriber::filter::EnvFilter;
-use tracing_subscriber::{
-    fmt::{self, layer, writer::WithMaxLevel},
-    layer::{self, Filter},
-    prelude::*,
-    registry, Registry,
-};
+use tracing_subscriber::{fmt, prelude::*, Registry};
+use validation::ValidationConfig;
+use viewer_auth::ViewerTokenVerifier;
 
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{watch, Mutex};
 
 #[derive(Debug)]
 pub struct TheState {
-    pub auth_token: String,
-    pub simulation: bool,
+    pub auth: AuthState,
+    /// Set when the relay simulates every org, always with a seed
+    pub simulation: Option<SimulationConfig>,
+    /// Per org scripts, only run when `scripts.dir` is set
+    pub scripts: ScriptConfig,
     pub orgs: Mutex<HashMap<String, Org>>,
-    pub scene: RwLock<Scene>,
+    pub lifecycle: LifecycleConfig,
+    pub throttle: ThrottleConfig,
+    pub limits: ConnectionLimits,
+    pub validation: ValidationConfig,
+    pub client_heartbeat: HeartbeatConfig,
+    pub game_server_heartbeat: HeartbeatConfig,
+    pub storage: Option<SceneStorage>,
+    pub metrics: Metrics,
+    /// Set once the listener is bound, cleared as soon as a shutdown signal arrives
+    pub accepting: AtomicBool,
+    /// Flips to `true` once the relay starts shutting down
+    pub shutdown: watch::Sender<bool>,
+}
+
+#[derive(Debug)]
+pub struct AuthState {
+    pub credentials: CredentialStore,
+    /// Verifies tokens on `/sub/:org`, viewers are not authenticated when unset
+    pub viewer_tokens: Option<ViewerTokenVerifier>,
+    pub lockout: AuthLockout,
+    /// Use `x-forwarded-for` as the client ip, only enable behind a proxy that sets it
+    pub trust_forwarded_for: bool,
+    /// sha256 of the token for `/admin`, the admin api answers 404 when unset
+    pub admin_token_sha256: Option<String>,
+    /// Browser origins allowed on `/sub/:org` and `/scene/:org`
+    pub origins: Arc<OriginConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct LifecycleConfig {
+    /// How long an org without viewers or game servers is kept around before it is removed
+    #[serde(rename = "org_grace_period_ms", deserialize_with = "millis")]
+    pub org_grace_period: Duration,
+    /// How long a disconnected game server can resume its session before its items are released
+    #[serde(rename = "resume_timeout_ms", deserialize_with = "millis")]
+    pub resume_timeout: Duration,
+    /// How long draining connections may take on shutdown before the relay exits anyway
+    #[serde(rename = "shutdown_deadline_ms", deserialize_with = "millis")]
+    pub shutdown_deadline: Duration,
+    /// How long peers are told to wait before reconnecting after a shutdown
+    #[serde(rename = "reconnect_delay_ms", deserialize_with = "millis")]
+    pub reconnect_delay: Duration,
+    /// How long the listener stays open after a shutdown signal while `/readyz` already
+    /// reports not ready, so load balancers stop routing new connections first
+    #[serde(rename = "unready_delay_ms", deserialize_with = "millis")]
+    pub unready_delay: Duration,
+}
+
+impl Default for LifecycleConfig {
+    fn default() -> Self {
+        Self {
+            org_grace_period: Duration::from_millis(DEFAULT_ORG_GRACE_PERIOD_MS),
+            resume_timeout: Duration::from_millis(DEFAULT_RESUME_TIMEOUT_MS),
+            shutdown_deadline: Duration::from_millis(DEFAULT_SHUTDOWN_DEADLINE_MS),
+            reconnect_delay: Duration::from_millis(DEFAULT_RECONNECT_DELAY_MS),
+            unready_delay: Duration::from_millis(DEFAULT_UNREADY_DELAY_MS),
+        }
+    }
 }
 
 impl TheState {
-    pub fn new(auth_token: String, simulation: bool) -> Self {
+    pub fn new(auth: AuthState, config: &Config) -> Self {
         Self {
             orgs: Mutex::new(HashMap::new()),
-            scene: RwLock::new(scene::create_test_scene()),
-            auth_token,
-            simulation,
+            auth,
+            simulation: config.server.simulate.then(|| {
+                let seed = config.simulation.seed.unwrap_or_else(rand::random);
+                info!(seed, mode = ?config.simulation.mode, "Simulating every org");
+                SimulationConfig {
+                    seed: Some(seed),
+                    ..config.simulation.clone()
+                }
+            }),
+            scripts: {
+                if let Some(dir) = &config.scripts.dir {
+                    info!(?dir, "Running org scripts");
+                }
+                config.scripts.clone()
+            },
+            lifecycle: config.lifecycle,
+            throttle: config.throttle,
+            limits: ConnectionLimits::new(config.limits),
+            validation: config.validation,
+            client_heartbeat: config.heartbeat.client,
+            game_server_heartbeat: config.heartbeat.game_server,
+            storage: config.storage.scene_dir.clone().map(SceneStorage::new),
+            metrics: Metrics::new(),
+            accepting: AtomicBool::new(false),
+            shutdown: watch::Sender::new(false),
         }
     }
 }
 
 pub type SharedState = Arc<TheState>;
 
+const DEFAULT_ORG_GRACE_PERIOD_MS: u64 = 30_000;
+const DEFAULT_RESUME_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_SHUTDOWN_DEADLINE_MS: u64 = 10_000;
+const DEFAULT_RECONNECT_DELAY_MS: u64 = 5_000;
+const DEFAULT_UNREADY_DELAY_MS: u64 = 0;
+
 #[tokio::main]
 #[instrument]
 async fn main() {
-    let env = std::env::var("ENV").unwrap_or("production".into());
-    if env == "development" {
-        tracing_subscriber::fmt().without_time().init();
+    let config = Config::load();
+
+    let logging = &config.logging;
+    if logging.env == Environment::Development {
+        tracing_subscriber::fmt()
+            .without_time()
+            .with_env_filter(logging.filter.as_deref().unwrap_or("info"))
+            .init();
     } else {
         let env_filter = EnvFilter::builder()
             .with_default_directive(LevelFilter::DEBUG.into())
-            .from_env()
-            .expect("Failed to create env filter invalid RUST_LOG env var");
+            .parse_lossy(logging.filter.as_deref().unwrap_or_default());
 
         let registry = Registry::default().with(env_filter).with(fmt::layer());
 
-        if let Ok(_) = std::env::var("AXIOM_TOKEN") {
-            let axiom_layer = tracing_axiom::builder()
+        if let Some(axiom_token) = &logging.axiom_token {
+            let deployment_id = logging
+                .deployment_id
+                .as_ref()
+                .map(|deployment_id| {
+                    format!(
+                        "{}-{}",
+                        deployment_id,
+                        logging.replica_id.as_deref().unwrap_or("unknown_replica")
+                    )
+                })
+                .unwrap_or("unknown_deployment".into());
+            let mut axiom_builder = tracing_axiom::builder()
+                .with_token(axiom_token)
                 .with_service_name("org")
-                .with_tags(&[(
-                    &"deployment_id",
-                    &std::env::var("RAILWAY_DEPLOYMENT_ID")
-                        .map(|s| {
-                            s + "-"
-                                + std::env::var("RAILWAY_DEPLOYMENT_ID")
-                                    .unwrap_or("unknown_replica".into())
-                                    .as_str()
-                        })
-                        .unwrap_or("unknown_deployment".into()),
-                )])
-                .with_tags(&[(&"service.name", "org".into())])
+                .with_tags(&[("deployment_id", &deployment_id)])
+                .with_tags(&[("service.name", "org")]);
+            if let Some(dataset) = &logging.axiom_dataset {
+                axiom_builder = axiom_builder.with_dataset(dataset);
+            }
+            let axiom_layer = axiom_builder
                 .layer()
                 .expect("Axiom layer failed to initialize");
 
@@ -87,23 +223,120 @@ async fn main() {
         }
     };
 
-    let auth_token = std::env::var("AUTH_TOKEN").expect("AUTH_TOKEN env var set");
-    let simulation = std::env::var("SIMULATE").unwrap_or_default() == "true";
-    let state = Arc::new(TheState::new(auth_token, simulation));
+    let auth_config = &config.auth;
+    let credentials = match (&auth_config.credentials_file, &auth_config.token) {
+        (Some(path), _) => {
+            CredentialStore::from_file(path.clone()).expect("Failed to load credentials file")
+        }
+        (None, Some(token)) => CredentialStore::from_token(token),
+        (None, None) => unreachable!("validated by Config::load"),
+    };
+    let viewer_tokens = match (
+        &auth_config.viewer_token_secret,
+        &auth_config.viewer_token_public_key_file,
+    ) {
+        (Some(secret), _) => Some(ViewerTokenVerifier::from_secret(secret)),
+        (None, Some(path)) => Some(
+            ViewerTokenVerifier::from_ed25519_pem(
+                &std::fs::read(path).expect("Failed to read viewer token public key"),
+            )
+            .expect("Failed to load viewer token public key"),
+        ),
+        (None, None) => {
+            warn!("Neither VIEWER_TOKEN_SECRET nor VIEWER_TOKEN_PUBLIC_KEY_FILE is set, viewers are not authenticated");
+            None
+        }
+    };
+    let auth = AuthState {
+        credentials,
+        viewer_tokens,
+        lockout: AuthLockout::new(auth_config.lockout),
+        trust_forwarded_for: auth_config.trust_forwarded_for,
+        admin_token_sha256: auth_config
+            .admin_token
+            .as_deref()
+            .map(credentials::hash_token),
+        origins: Arc::new(config.origins.clone()),
+    };
+    if config.origins.is_unrestricted() {
+        warn!("No allowed origins configured, viewers can connect from any website");
+    }
+    let lifecycle = config.lifecycle;
+    let state = Arc::new(TheState::new(auth, &config));
+
+    tokio::spawn(game_socket::broadcast_task(state.clone()));
+    tokio::spawn(credentials::reload_credentials_task(
+        state.clone(),
+        auth_config.credentials_reload_interval,
+    ));
 
     let app = Router::new()
         .route("/sub/:org", get(client_handler))
         .route("/game/:org", get(game_handler))
-        .route("/scene/:org", get(get_scene))
-        .with_state(state);
+        .route(
+            "/scene/:org",
+            get(get_scene).layer(origin::scene_cors(state.auth.origins.clone())),
+        )
+        .route("/metrics", get(metrics::get_metrics))
+        .route("/healthz", get(health::get_healthz))
+        .route("/readyz", get(health::get_readyz))
+        .nest("/admin", admin::router(state.clone()))
+        .with_state(state.clone());
+
+    let host = SocketAddr::new(config.server.host, config.server.port);
+    let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+    let stop_accepting = shutdown::stop_accepting(state.clone());
+    match (&config.tls.cert_file, &config.tls.key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            let rustls_config = tls::load(cert_file, key_file)
+                .await
+                .expect("Failed to load TLS certificate");
+            tokio::spawn(tls::reload_certificate_task(
+                rustls_config.clone(),
+                cert_file.clone(),
+                key_file.clone(),
+                config.tls.reload_interval,
+            ));
 
-    let port = std::env::var("PORT").unwrap_or("3002".to_string());
-    let host = format!("0.0.0.0:{}", port);
-    info!("Running server on {}", host);
+            let handle = axum_server::Handle::new();
+            let handle_for_listening_task = handle.clone();
+            let state_for_listening_task = state.clone();
+            tokio::spawn(async move {
+                if handle_for_listening_task.listening().await.is_some() {
+                    state_for_listening_task
+                        .accepting
+                        .store(true, Ordering::Relaxed);
+                }
+                stop_accepting.await;
+                handle_for_listening_task.graceful_shutdown(None);
+            });
 
-    // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind(host).await.unwrap();
-    axum::serve(listener, app.into_make_service())
+            info!("Running server with TLS on {}", host);
+            axum_server::bind_rustls(host, rustls_config)
+                .handle(handle)
+                .serve(make_service)
+                .await
+                .unwrap();
+        }
+        _ => {
+            info!("Running server on {}", host);
+            let listener = tokio::net::TcpListener::bind(host).await.unwrap();
+            state.accepting.store(true, Ordering::Relaxed);
+            axum::serve(listener, make_service)
+                .with_graceful_shutdown(stop_accepting)
+                .await
+                .unwrap();
+        }
+    }
+
+    info!(
+        deadline_ms = lifecycle.shutdown_deadline.as_millis() as u64,
+        "Stopped accepting connections, draining"
+    );
+    if tokio::time::timeout(lifecycle.shutdown_deadline, shutdown::drain(state))
         .await
-        .unwrap();
+        .is_err()
+    {
+        warn!("Shutdown deadline exceeded, exiting with connections still open");
+    }
 }
@@ -0,0 +1,221 @@
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tracing::{error, instrument};
+
+use crate::SharedState;
+
+/// Prometheus metrics for the relay, served in the text format on `/metrics`
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    orgs: IntGauge,
+    /// Labelled by org id, which comes from the request path. Series only exist for orgs held
+    /// in memory, which the org limit bounds, and are removed when the org is dropped
+    viewers: IntGaugeVec,
+    game_servers: IntGauge,
+    pub messages_received: IntCounter,
+    pub messages_parsed: IntCounter,
+    pub messages_failed: IntCounter,
+    /// Game server input dropped or rejected, by the limit that was hit
+    pub input_limited: IntCounterVec,
+    /// Game server updates that failed validation, by reason
+    pub updates_rejected: IntCounterVec,
+    pub batches_sent: IntCounter,
+    /// Bytes queued to viewers, a batch counts once for every viewer it is sent to
+    pub bytes_sent: IntCounter,
+    pub send_errors: IntCounter,
+    pub batch_size: Histogram,
+    pub broadcast_latency: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let orgs = IntGauge::new("relay_orgs", "Orgs currently held in memory").unwrap();
+        let viewers = IntGaugeVec::new(
+            Opts::new("relay_viewers", "Viewers connected to an org"),
+            &["org"],
+        )
+        .unwrap();
+        let game_servers = IntGauge::new(
+            "relay_game_servers",
+            "Game server sessions, including detached ones waiting to be resumed",
+        )
+        .unwrap();
+        let messages_received = IntCounter::new(
+            "relay_game_server_messages_received_total",
+            "Text messages received from game servers",
+        )
+        .unwrap();
+        let messages_parsed = IntCounter::new(
+            "relay_game_server_messages_parsed_total",
+            "Game server text messages that parsed as an update, a batch or a control message",
+        )
+        .unwrap();
+        let messages_failed = IntCounter::new(
+            "relay_game_server_messages_failed_total",
+            "Game server messages that could not be parsed",
+        )
+        .unwrap();
+        let input_limited = IntCounterVec::new(
+            Opts::new(
+                "relay_game_server_input_limited_total",
+                "Game server updates dropped or frames rejected by input limits",
+            ),
+            &["limit"],
+        )
+        .unwrap();
+        let updates_rejected = IntCounterVec::new(
+            Opts::new(
+                "relay_game_server_updates_rejected_total",
+                "Game server updates rejected by validation",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let batches_sent = IntCounter::new(
+            "relay_batches_sent_total",
+            "Batches of scene updates broadcast to viewers",
+        )
+        .unwrap();
+        let bytes_sent = IntCounter::new(
+            "relay_sent_bytes_total",
+            "Bytes of scene updates sent to viewers",
+        )
+        .unwrap();
+        let send_errors = IntCounter::new(
+            "relay_client_send_errors_total",
+            "Messages that could not be queued to a viewer",
+        )
+        .unwrap();
+        let batch_size = Histogram::with_opts(
+            HistogramOpts::new("relay_batch_size", "Scene updates per broadcast batch")
+                .buckets(exponential_buckets(1.0, 2.0, 12).unwrap()),
+        )
+        .unwrap();
+        let broadcast_latency = Histogram::with_opts(
+            HistogramOpts::new(
+                "relay_broadcast_latency_seconds",
+                "Time to serialize a tick and queue it to every viewer",
+            )
+            .buckets(exponential_buckets(0.000_05, 2.0, 14).unwrap()),
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(orgs.clone())).unwrap();
+        registry.register(Box::new(viewers.clone())).unwrap();
+        registry.register(Box::new(game_servers.clone())).unwrap();
+        registry
+            .register(Box::new(messages_received.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(messages_parsed.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(messages_failed.clone()))
+            .unwrap();
+        registry.register(Box::new(input_limited.clone())).unwrap();
+        registry
+            .register(Box::new(updates_rejected.clone()))
+            .unwrap();
+        registry.register(Box::new(batches_sent.clone())).unwrap();
+        registry.register(Box::new(bytes_sent.clone())).unwrap();
+        registry.register(Box::new(send_errors.clone())).unwrap();
+        registry.register(Box::new(batch_size.clone())).unwrap();
+        registry
+            .register(Box::new(broadcast_latency.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            orgs,
+            viewers,
+            game_servers,
+            messages_received,
+            messages_parsed,
+            messages_failed,
+            input_limited,
+            updates_rejected,
+            batches_sent,
+            bytes_sent,
+            send_errors,
+            batch_size,
+            broadcast_latency,
+        }
+    }
+
+    /// Removes the series of an org that was dropped from memory
+    pub fn forget_org(&self, org_id: &str) {
+        let _ = self.viewers.remove_label_values(&[org_id]);
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Gauges are read from the orgs on every scrape so they cannot drift from the real counts
+#[instrument(skip(state))]
+pub async fn get_metrics(State(state): State<SharedState>) -> Response {
+    let metrics = &state.metrics;
+    {
+        let current_orgs = state.orgs.lock().await;
+        metrics.orgs.set(current_orgs.len() as i64);
+        for org in current_orgs.values() {
+            metrics
+                .viewers
+                .with_label_values(&[&org.id])
+                .set(org.clients.len() as i64);
+        }
+        metrics.game_servers.set(
+            current_orgs
+                .values()
+                .map(|org| org.game_servers.len() as i64)
+                .sum(),
+        );
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(err) = encoder.encode(&metrics.registry.gather(), &mut buffer) {
+        error!(error = ?err, "Failed to encode metrics");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    ([(header::CONTENT_TYPE, encoder.format_type())], buffer).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn viewer_series(metrics: &Metrics) -> usize {
+        metrics
+            .registry
+            .gather()
+            .iter()
+            .filter(|family| family.get_name() == "relay_viewers")
+            .map(|family| family.get_metric().len())
+            .sum()
+    }
+
+    #[test]
+    fn dropped_orgs_lose_their_viewer_series() {
+        let metrics = Metrics::new();
+        metrics.viewers.with_label_values(&["a"]).set(2);
+        metrics.viewers.with_label_values(&["b"]).set(1);
+        assert_eq!(viewer_series(&metrics), 2);
+
+        metrics.forget_org("a");
+        metrics.forget_org("unknown");
+        assert_eq!(viewer_series(&metrics), 1);
+    }
+}
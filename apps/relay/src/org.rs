@@ -1,25 +1,488 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
 use axum::extract::ws::Message;
-use tokio::sync::mpsc::UnboundedSender;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+use tokio::{
+    sync::{
+        mpsc::{error::SendError, UnboundedSender},
+        Mutex, Notify,
+    },
+    task::AbortHandle,
+    time::sleep,
+};
+use tracing::{info, instrument};
+
+use crate::{
+    data::color::Color,
+    error::RelayError,
+    game_socket::merge_updates,
+    scene::{self, MeshType, Scene, SceneItem, SceneUpdate},
+    scripting::ScriptHandle,
+    simulation::{physics::Impulse, SimulationHandle},
+    validation::InvalidUpdate,
+    SharedState,
+};
+
+const RESUME_TOKEN_LENGTH: usize = 32;
 
 #[derive(Debug)]
 pub struct Org {
     pub id: String,
     pub clients: Vec<Client>,
-    pub server_connected: bool,
+    pub game_servers: Vec<GameServer>,
+    pub scene: Scene,
+    /// Pending cleanup scheduled when the last viewer or game server left
+    reap_task: Option<AbortHandle>,
+    /// Steps the built in simulation while the relay runs with `--simulate`
+    simulation: Option<SimulationHandle>,
+    /// Runs the org's script while `scripts.dir` is set
+    script: Option<ScriptHandle>,
+    /// Updates applied to the scene since the last tick, viewers get them with the next one
+    outbox: Vec<SceneUpdate>,
+    /// Index of the next tick sent to viewers
+    tick: u64,
 }
 
 impl Org {
-    pub fn new(clients: Vec<Client>, server_connected: bool, id: String) -> Self {
+    pub fn new(id: String) -> Self {
+        info!(org_id = id, "Org created");
         Self {
-            clients,
-            server_connected,
+            clients: vec![],
             id,
+            game_servers: vec![],
+            scene: scene::create_test_scene(),
+            reap_task: None,
+            simulation: None,
+            script: None,
+            outbox: vec![],
+            tick: 0,
+        }
+    }
+
+    pub fn server_connected(&self) -> bool {
+        !self.game_servers.is_empty()
+    }
+
+    /// An org is idle once neither viewers nor game servers are attached to it
+    pub fn is_idle(&self) -> bool {
+        self.clients.is_empty() && self.game_servers.is_empty()
+    }
+
+    /// Cancels a pending cleanup because someone joined the org again
+    fn mark_active(&mut self) {
+        if let Some(reap_task) = self.reap_task.take() {
+            reap_task.abort();
+            info!(org_id = self.id, "Org active again, cancelled cleanup");
+        }
+    }
+
+    /// Returns the game server that owns `item_id`, if any
+    pub fn owner_of(&self, item_id: &str) -> Option<&GameServer> {
+        self.game_servers
+            .iter()
+            .find(|server| server.ownership.owns(item_id))
+    }
+
+    /// Whether `server_id` is allowed to publish updates for `item_id`.
+    /// Unowned items can be updated by any server.
+    pub fn can_update(&self, server_id: usize, item_id: &str) -> bool {
+        self.owner_of(item_id)
+            .is_none_or(|owner| owner.server_id == server_id)
+    }
+
+    /// Returns the id of a connected game server whose claim overlaps `ownership`
+    pub fn conflicting_server(&self, ownership: &ItemOwnership) -> Option<usize> {
+        self.game_servers
+            .iter()
+            .find(|server| server.ownership.overlaps(ownership))
+            .map(|server| server.server_id)
+    }
+
+    pub fn game_server_mut(&mut self, server_id: usize) -> Option<&mut GameServer> {
+        self.game_servers
+            .iter_mut()
+            .find(|server| server.server_id == server_id)
+    }
+
+    /// Returns the game server session a reconnecting server asked to resume
+    pub fn resumable_server(&mut self, resume_token: &str) -> Option<&mut GameServer> {
+        self.game_servers
+            .iter_mut()
+            .find(|server| server.resume_token == resume_token)
+    }
+
+    /// Removes a game server and returns it so its owned items can be released
+    pub fn remove_game_server(&mut self, server_id: usize) -> Option<GameServer> {
+        let index = self
+            .game_servers
+            .iter()
+            .position(|server| server.server_id == server_id)?;
+        Some(self.game_servers.remove(index))
+    }
+
+    /// Queues a push for an item the physics simulation moves
+    pub fn push_impulse(&self, item_id: &str, impulse: Impulse) -> Result<(), RelayError> {
+        let simulation = self
+            .simulation
+            .as_ref()
+            .ok_or(RelayError::PhysicsNotRunning)?;
+        if !impulse.is_finite() {
+            return Err(InvalidUpdate::NonFiniteImpulse.into());
+        }
+        if !self.scene.items.iter().any(|item| item.id == item_id) {
+            return Err(InvalidUpdate::UnknownItem.into());
+        }
+        simulation.push_impulse(item_id, impulse)
+    }
+
+    /// Applies `updates` to the scene right away and queues them for the org's next tick
+    pub fn broadcast(&mut self, updates: Vec<SceneUpdate>) {
+        self.apply_updates(&updates);
+        self.outbox.extend(updates);
+    }
+
+    /// Takes the index of the next tick and what was queued for it, merged per item
+    pub fn next_tick(&mut self) -> (u64, Vec<SceneUpdate>) {
+        let tick = self.tick;
+        self.tick += 1;
+        (tick, merge_updates(self.outbox.drain(..)))
+    }
+
+    /// Applies a broadcast batch to the org scene so `/scene/:org` reflects what viewers see
+    fn apply_updates(&mut self, updates: &[SceneUpdate]) {
+        for update in updates {
+            if update.despawned {
+                self.scene.items.retain(|item| item.id != update.id);
+                continue;
+            }
+
+            if let Some(item) = self
+                .scene
+                .items
+                .iter_mut()
+                .find(|item| item.id == update.id)
+            {
+                item.position = update.position.unwrap_or(item.position);
+                item.rotation = update.rotation.unwrap_or(item.rotation);
+                item.color = update.color.unwrap_or(item.color);
+                continue;
+            }
+
+            // Only updates allowed to spawn items get this far, see `ValidationConfig`
+            self.scene.items.push(SceneItem {
+                mesh_type: update.mesh_type.clone().unwrap_or(MeshType::Cube),
+                id: update.id.clone(),
+                position: update.position.unwrap_or_default(),
+                rotation: update.rotation.unwrap_or_default(),
+                color: update.color.unwrap_or(Color::white()),
+                scale: update.scale.unwrap_or_else(scene::unit_scale),
+            });
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Client {
     pub client_id: usize,
-    pub tx: UnboundedSender<Message>,
+    pub connected_at: SystemTime,
+    tx: UnboundedSender<Message>,
+    /// Messages queued for the client's socket that have not been written yet
+    queue_depth: Arc<AtomicUsize>,
+}
+
+impl Client {
+    pub fn new(client_id: usize, tx: UnboundedSender<Message>) -> Self {
+        Self {
+            client_id,
+            connected_at: SystemTime::now(),
+            tx,
+            queue_depth: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Queues a message for the client's socket
+    pub fn send(&self, message: Message) -> Result<(), SendError<Message>> {
+        self.queue_depth.fetch_add(1, Ordering::Relaxed);
+        self.tx.send(message).inspect_err(|_| {
+            self.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+
+    /// Called by the socket's message task for every message it takes off the queue
+    pub fn message_dequeued(&self) {
+        self.queue_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub struct GameServer {
+    pub server_id: usize,
+    pub ownership: ItemOwnership,
+    pub on_disconnect: DisconnectPolicy,
+    /// Token handed to the game server on connect, used to resume the session after a drop
+    pub resume_token: String,
+    /// Set while the socket is gone and the session is waiting to be resumed
+    pub release_task: Option<AbortHandle>,
+    /// Updates received but not yet broadcast, kept with the session so a resume or
+    /// shutdown can still flush them
+    pub pending_messages: Arc<Mutex<Vec<SceneUpdate>>>,
+    /// Notified to make the game server's socket close itself
+    pub disconnect: Arc<Notify>,
+}
+
+impl GameServer {
+    pub fn new(
+        server_id: usize,
+        ownership: ItemOwnership,
+        on_disconnect: DisconnectPolicy,
+    ) -> Self {
+        Self {
+            server_id,
+            ownership,
+            on_disconnect,
+            resume_token: rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(RESUME_TOKEN_LENGTH)
+                .map(char::from)
+                .collect(),
+            release_task: None,
+            pending_messages: Arc::new(Mutex::new(vec![])),
+            disconnect: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn is_detached(&self) -> bool {
+        self.release_task.is_some()
+    }
+
+    /// Cancels the pending release so a reconnecting server keeps its session
+    pub fn reattach(&mut self) {
+        if let Some(release_task) = self.release_task.take() {
+            release_task.abort();
+        }
+    }
+}
+
+/// What happens to the items a game server owns once it disconnects
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DisconnectPolicy {
+    /// Items stay in the scene and can be claimed by another server
+    #[default]
+    Release,
+    /// Items are removed from the scene and viewers are told to drop them
+    Despawn,
+}
+
+/// The set of item ids a game server has claimed, either explicitly or by id prefix
+#[derive(Debug, Clone, Default)]
+pub struct ItemOwnership {
+    pub items: Vec<String>,
+    pub prefix: Option<String>,
+}
+
+impl ItemOwnership {
+    pub fn new(items: Option<&str>, prefix: Option<String>) -> Self {
+        Self {
+            items: items
+                .map(|items| {
+                    items
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|id| !id.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            prefix: prefix.filter(|prefix| !prefix.is_empty()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty() && self.prefix.is_none()
+    }
+
+    pub fn owns(&self, item_id: &str) -> bool {
+        self.items.iter().any(|id| id == item_id)
+            || self
+                .prefix
+                .as_ref()
+                .is_some_and(|prefix| item_id.starts_with(prefix.as_str()))
+    }
+
+    pub fn overlaps(&self, other: &ItemOwnership) -> bool {
+        let prefixes_overlap = match (&self.prefix, &other.prefix) {
+            (Some(a), Some(b)) => a.starts_with(b.as_str()) || b.starts_with(a.as_str()),
+            _ => false,
+        };
+
+        prefixes_overlap
+            || self.items.iter().any(|id| other.owns(id))
+            || other.items.iter().any(|id| self.owns(id))
+    }
+
+    /// Ids of the items in `scene` covered by this claim
+    pub fn owned_items(&self, scene: &Scene) -> Vec<String> {
+        scene
+            .items
+            .iter()
+            .filter(|item| self.owns(&item.id))
+            .map(|item| item.id.clone())
+            .collect()
+    }
+}
+
+/// Reads the stored scene of an org that is not running yet, for [`join_org`]. The org map is
+/// not held while reading so a slow disk does not hold up every other org.
+pub async fn load_stored_scene(org_id: &str, state: &SharedState) -> Option<Scene> {
+    let storage = state.storage.as_ref()?;
+    if state.orgs.lock().await.contains_key(org_id) {
+        return None;
+    }
+    storage.load(org_id).await
+}
+
+/// Looks up an org, creating it if needed, and cancels any cleanup scheduled for it.
+/// New orgs start from `stored_scene` when there is one, see [`load_stored_scene`].
+pub fn join_org<'a>(
+    orgs: &'a mut HashMap<String, Org>,
+    org_id: &str,
+    stored_scene: Option<Scene>,
+    state: &SharedState,
+) -> &'a mut Org {
+    let org = match orgs.entry(org_id.to_string()) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let mut org = Org::new(org_id.to_string());
+            if let Some(scene) = stored_scene {
+                org.scene = scene;
+            }
+            org.simulation = state
+                .simulation
+                .as_ref()
+                .map(|simulation| SimulationHandle::spawn(org_id, simulation.clone(), state));
+            org.script = state
+                .scripts
+                .dir
+                .as_ref()
+                .map(|dir| ScriptHandle::spawn(org_id, dir, state));
+            entry.insert(org)
+        }
+    };
+    org.mark_active();
+    org
+}
+
+/// Schedules removal of `org` after the configured grace period if nobody is attached to it.
+/// The cleanup is cancelled by [`join_org`] when a viewer or game server comes back in time.
+pub fn schedule_reap_if_idle(org: &mut Org, state: &SharedState) {
+    if !org.is_idle() || org.reap_task.is_some() {
+        return;
+    }
+
+    let grace_period = state.lifecycle.org_grace_period;
+    info!(
+        org_id = org.id,
+        grace_period_ms = grace_period.as_millis() as u64,
+        "Org idle, scheduling cleanup"
+    );
+    let reap_task = tokio::spawn(reap_org(org.id.clone(), state.clone()));
+    org.reap_task = Some(reap_task.abort_handle());
+}
+
+#[instrument(skip(state))]
+async fn reap_org(org_id: String, state: SharedState) {
+    sleep(state.lifecycle.org_grace_period).await;
+    let mut current_orgs = state.orgs.lock().await;
+    match current_orgs.get_mut(&org_id) {
+        Some(org) if org.is_idle() => {
+            current_orgs.remove(&org_id);
+            state.limits.forget_org(&org_id);
+            state.metrics.forget_org(&org_id);
+            info!(org_id, "Org removed after grace period");
+        }
+        Some(org) => org.reap_task = None,
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claim(items: Option<&str>, prefix: Option<&str>) -> ItemOwnership {
+        ItemOwnership::new(items, prefix.map(String::from))
+    }
+
+    #[test]
+    fn overlapping_claims() {
+        let overlapping = [
+            (claim(Some("a, b"), None), claim(Some("b"), None)),
+            (claim(Some("player-1"), None), claim(None, Some("player-"))),
+            (claim(None, Some("player-")), claim(None, Some("player-1"))),
+            (claim(None, Some("npc")), claim(None, Some("npc"))),
+        ];
+        for (a, b) in overlapping {
+            assert!(a.overlaps(&b), "{:?} {:?}", a, b);
+            assert!(b.overlaps(&a), "{:?} {:?}", b, a);
+        }
+    }
+
+    #[test]
+    fn disjoint_claims() {
+        let disjoint = [
+            (claim(Some("a, b"), None), claim(Some("c"), None)),
+            (claim(Some("npc-1"), None), claim(None, Some("player-"))),
+            (claim(None, Some("player-")), claim(None, Some("npc-"))),
+            (claim(Some(" , "), Some("")), claim(Some("a"), Some("a"))),
+        ];
+        for (a, b) in disjoint {
+            assert!(!a.overlaps(&b), "{:?} {:?}", a, b);
+            assert!(!b.overlaps(&a), "{:?} {:?}", b, a);
+        }
+    }
+
+    #[test]
+    fn only_the_owner_updates_claimed_items() {
+        let mut org = Org::new("org".into());
+        org.game_servers.push(GameServer::new(
+            1,
+            claim(Some("0"), None),
+            DisconnectPolicy::Release,
+        ));
+        org.game_servers.push(GameServer::new(
+            2,
+            claim(None, Some("player-")),
+            DisconnectPolicy::Release,
+        ));
+
+        assert!(org.can_update(1, "0"));
+        assert!(!org.can_update(2, "0"));
+        assert!(org.can_update(2, "player-7"));
+        assert!(!org.can_update(1, "player-7"));
+        // Unclaimed items are open to every server
+        assert!(org.can_update(1, "1"));
+        assert!(org.can_update(2, "1"));
+
+        assert_eq!(
+            org.conflicting_server(&claim(Some("player-3"), None)),
+            Some(2)
+        );
+        assert_eq!(org.conflicting_server(&claim(Some("1"), None)), None);
+
+        org.remove_game_server(1);
+        assert!(org.can_update(2, "0"));
+    }
 }
@@ -0,0 +1,157 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::http::{request::Parts, HeaderValue, Method};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// Origin that allows every origin
+const ANY_ORIGIN: &str = "*";
+
+/// Browser origins allowed to open viewer sockets and read scenes. Requests without an `Origin`
+/// header do not come from a browser and cannot be hijacked cross-site, they are always allowed.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct OriginConfig {
+    /// Origins allowed for orgs without their own entry, e.g. `https://app.example.com`
+    pub allowed: Vec<String>,
+    /// Per org allowlists, replacing `allowed` for that org
+    pub orgs: HashMap<String, Vec<String>>,
+}
+
+impl OriginConfig {
+    /// Every origin is allowed until an allowlist is configured
+    pub fn is_unrestricted(&self) -> bool {
+        self.allowed.is_empty() && self.orgs.is_empty()
+    }
+
+    pub fn is_allowed(&self, org_id: &str, origin: Option<&str>) -> bool {
+        let Some(origin) = origin else {
+            return true;
+        };
+        if self.is_unrestricted() {
+            return true;
+        }
+        let origin = normalize(origin);
+        self.orgs
+            .get(org_id)
+            .unwrap_or(&self.allowed)
+            .iter()
+            .any(|allowed| allowed == ANY_ORIGIN || normalize(allowed) == origin)
+    }
+
+    /// Entries that are not a bare `scheme://host[:port]` origin, browsers never send those
+    pub fn invalid_origins(&self) -> Vec<&str> {
+        self.allowed
+            .iter()
+            .chain(self.orgs.values().flatten())
+            .map(String::as_str)
+            .filter(|origin| *origin != ANY_ORIGIN && !is_origin(origin))
+            .collect()
+    }
+}
+
+fn normalize(origin: &str) -> String {
+    origin.trim_end_matches('/').to_ascii_lowercase()
+}
+
+fn is_origin(origin: &str) -> bool {
+    let Some((scheme, host)) = origin.split_once("://") else {
+        return false;
+    };
+    matches!(scheme, "http" | "https")
+        && !host.is_empty()
+        && !host.trim_end_matches('/').contains(['/', '?', '#'])
+}
+
+/// CORS for `/scene/:org`, the allowed origins depend on the org in the path
+pub fn scene_cors(origins: Arc<OriginConfig>) -> CorsLayer {
+    CorsLayer::new()
+        .allow_methods([Method::GET])
+        .allow_origin(AllowOrigin::predicate(
+            move |origin: &HeaderValue, parts: &Parts| {
+                // The raw path is still percent encoded, `Path` decodes it the same way
+                let org_id = percent_decode_str(parts.uri.path().trim_start_matches("/scene/"))
+                    .decode_utf8_lossy();
+                origin
+                    .to_str()
+                    .is_ok_and(|origin| origins.is_allowed(&org_id, Some(origin)))
+            },
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(allowed: &[&str], orgs: &[(&str, &[&str])]) -> OriginConfig {
+        let origins = |origins: &[&str]| origins.iter().map(|origin| origin.to_string()).collect();
+        OriginConfig {
+            allowed: origins(allowed),
+            orgs: orgs
+                .iter()
+                .map(|(org_id, allowed)| (org_id.to_string(), origins(allowed)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn normalizes_case_and_trailing_slash() {
+        let config = config(&["https://App.example.com/"], &[]);
+        assert!(config.is_allowed("org", Some("https://app.example.com")));
+        assert!(config.is_allowed("org", Some("HTTPS://APP.EXAMPLE.COM/")));
+        assert!(!config.is_allowed("org", Some("https://app.example.com:8443")));
+        assert!(!config.is_allowed("org", Some("http://app.example.com")));
+    }
+
+    #[test]
+    fn allows_everything_until_restricted() {
+        let config = config(&[], &[]);
+        assert!(config.is_unrestricted());
+        assert!(config.is_allowed("org", Some("https://evil.example.com")));
+    }
+
+    #[test]
+    fn always_allows_requests_without_origin() {
+        let config = config(&["https://app.example.com"], &[]);
+        assert!(config.is_allowed("org", None));
+    }
+
+    #[test]
+    fn org_allowlist_replaces_default() {
+        let config = config(
+            &["https://app.example.com"],
+            &[("special", &["https://special.example.com"])],
+        );
+        assert!(config.is_allowed("special", Some("https://special.example.com")));
+        assert!(!config.is_allowed("special", Some("https://app.example.com")));
+        assert!(config.is_allowed("other", Some("https://app.example.com")));
+    }
+
+    #[test]
+    fn wildcard_allows_any_origin() {
+        let config = config(&["*"], &[]);
+        assert!(config.is_allowed("org", Some("https://anything.example.com")));
+    }
+
+    #[test]
+    fn reports_entries_that_are_not_origins() {
+        let config = config(
+            &["https://app.example.com/", "*", "app.example.com"],
+            &[(
+                "org",
+                &["https://app.example.com/path", "ftp://files.example.com"],
+            )],
+        );
+        let mut invalid = config.invalid_origins();
+        invalid.sort();
+        assert_eq!(
+            invalid,
+            [
+                "app.example.com",
+                "ftp://files.example.com",
+                "https://app.example.com/path"
+            ]
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use axum::{
-    extract::State,
+    extract::{Path, State},
+    http::StatusCode,
     response::{IntoResponse, Response},
     Json,
 };
@@ -9,7 +10,7 @@ use tracing::{info, instrument};
 
 use crate::{
     data::color::{self, Color},
-    SharedState,
+    org, SharedState,
 };
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -21,11 +22,20 @@ pub struct Scene {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all(serialize = "camelCase"))]
 pub struct SceneItem {
+    /// Stored scenes are written in camelCase and read back with it
+    #[serde(alias = "meshType")]
     pub mesh_type: MeshType,
     pub id: String,
     pub position: (f32, f32, f32),
     pub rotation: (f32, f32, f32),
     pub color: Color,
+    /// Size along each axis, physics sizes colliders from it
+    #[serde(default = "unit_scale")]
+    pub scale: (f32, f32, f32),
+}
+
+pub fn unit_scale() -> (f32, f32, f32) {
+    (1.0, 1.0, 1.0)
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -36,6 +46,20 @@ pub enum MeshType {
     Plane,
 }
 
+/// An update that changes nothing, tests fill in the fields they need
+#[cfg(test)]
+pub fn test_update(id: &str) -> SceneUpdate {
+    SceneUpdate {
+        id: id.into(),
+        rotation: None,
+        position: None,
+        color: None,
+        despawned: false,
+        mesh_type: None,
+        scale: None,
+    }
+}
+
 pub fn create_test_scene() -> Scene {
     Scene {
         name: "test scene".into(),
@@ -46,6 +70,7 @@ pub fn create_test_scene() -> Scene {
                 position: (0.0, 0.0, 0.0),
                 rotation: (0.0, 0.0, 0.0),
                 color: color::Color::from_hex("#FF0000").unwrap(),
+                scale: unit_scale(),
             },
             SceneItem {
                 id: "1".into(),
@@ -53,6 +78,7 @@ pub fn create_test_scene() -> Scene {
                 position: (0.0, 0.0, 0.0),
                 rotation: (-0.0, 0.0, -0.0),
                 color: color::Color::from_hex("#FF0000").unwrap(),
+                scale: unit_scale(),
             },
             SceneItem {
                 id: "2".into(),
@@ -60,6 +86,7 @@ pub fn create_test_scene() -> Scene {
                 position: (-0.0, 0.0, 0.0),
                 rotation: (0.0, 0.0, 0.0),
                 color: color::Color::from_hex("#FF0000").unwrap(),
+                scale: unit_scale(),
             },
             SceneItem {
                 id: "3".into(),
@@ -67,18 +94,12 @@ pub fn create_test_scene() -> Scene {
                 position: (-0.0, 0.0, 0.0),
                 rotation: (0.0, 0.0, 300.),
                 color: color::Color::from_hex("#FF0000").unwrap(),
+                scale: unit_scale(),
             },
         ],
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(untagged)]
-pub enum SceneUpdateType {
-    Color(Color),
-    Rotation(Vec<f32>),
-}
-
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all(serialize = "camelCase"))]
 pub struct SceneUpdate {
@@ -86,11 +107,46 @@ pub struct SceneUpdate {
     pub rotation: Option<(f32, f32, f32)>,
     pub position: Option<(f32, f32, f32)>,
     pub color: Option<Color>,
+    /// Set when the item was removed from the scene, e.g. because its owning game server left
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub despawned: bool,
+    /// Only set when the update spawns the item, viewers create it with this mesh and scale
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mesh_type: Option<MeshType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<(f32, f32, f32)>,
+}
+
+impl SceneUpdate {
+    pub fn despawn(id: String) -> Self {
+        Self {
+            id,
+            rotation: None,
+            position: None,
+            color: None,
+            despawned: true,
+            mesh_type: None,
+            scale: None,
+        }
+    }
 }
 
+/// Serves the running org's scene, or the stored one while nobody is attached to the org
 #[instrument(skip(state))]
-pub async fn get_scene(State(state): State<SharedState>) -> Response {
-    info!("Getting scene");
-    let scene = state.scene.read().await.clone();
-    Json(scene).into_response()
+pub async fn get_scene(Path(org_id): Path<String>, State(state): State<SharedState>) -> Response {
+    info!(org_id, "Getting scene");
+    let running = state
+        .orgs
+        .lock()
+        .await
+        .get(&org_id)
+        .map(|org| org.scene.clone());
+    let scene = match running {
+        Some(scene) => Some(scene),
+        None => org::load_stored_scene(&org_id, &state).await,
+    };
+    match scene {
+        Some(scene) => Json(scene).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
 }
@@ -0,0 +1,648 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use rhai::{
+    module_resolvers::DummyModuleResolver, Array, CallFnOptions, Dynamic, Engine, EvalAltResult,
+    ImmutableString, Map, Scope, AST,
+};
+use serde::Deserialize;
+use tokio::{
+    task::{self, AbortHandle},
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, error, info, instrument};
+
+use crate::{
+    config::millis,
+    data::color::Color,
+    org::Org,
+    scene::{self, MeshType, Scene, SceneItem, SceneUpdate},
+    util,
+    validation::{InvalidUpdate, ValidationConfig},
+    SharedState,
+};
+
+const DEFAULT_RELOAD_INTERVAL_MS: u64 = 1_000;
+const DEFAULT_MAX_OPERATIONS: u64 = 100_000;
+const DEFAULT_MAX_CALL_DEPTH: usize = 32;
+const DEFAULT_MAX_STRING_SIZE: usize = 10_000;
+const DEFAULT_MAX_COLLECTION_SIZE: usize = 10_000;
+
+/// Rhai scripts for designers, `<dir>/<org id>.rhai` is ticked at the simulation interval while
+/// the org exists. Scripts cannot reach the file system or the network, the limits bound what
+/// one tick can cost.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptConfig {
+    /// Scripting is off when unset
+    pub dir: Option<PathBuf>,
+    /// How often scripts are checked for changes
+    #[serde(rename = "reload_interval_ms", deserialize_with = "millis")]
+    pub reload_interval: Duration,
+    /// Operations one tick may run, a script over it is stopped until its file changes
+    pub max_operations: u64,
+    /// Deepest nesting of function calls
+    pub max_call_depth: usize,
+    /// Longest string a script may build, in bytes
+    pub max_string_size: usize,
+    /// Most elements an array or map built by a script may hold
+    pub max_collection_size: usize,
+}
+
+impl Default for ScriptConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            reload_interval: Duration::from_millis(DEFAULT_RELOAD_INTERVAL_MS),
+            max_operations: DEFAULT_MAX_OPERATIONS,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_string_size: DEFAULT_MAX_STRING_SIZE,
+            max_collection_size: DEFAULT_MAX_COLLECTION_SIZE,
+        }
+    }
+}
+
+/// What a script asked for during one tick, applied once the tick is over
+#[derive(Debug)]
+enum Operation {
+    Spawn(SceneItem),
+    /// Changes or despawns an item
+    Update(SceneUpdate),
+}
+
+/// A compiled script and the value it sees as `this`, kept between ticks
+struct Script {
+    ast: AST,
+    state: Dynamic,
+}
+
+/// Loads the org's script and runs its ticks
+struct ScriptRunner {
+    path: PathBuf,
+    engine: Engine,
+    operations: Arc<Mutex<Vec<Operation>>>,
+    script: Option<Script>,
+    /// Modification time of the file last read, a script that failed waits for the next change
+    modified: Option<SystemTime>,
+}
+
+impl ScriptRunner {
+    fn new(org_id: &str, dir: &Path, config: &ScriptConfig) -> Self {
+        let operations = Arc::new(Mutex::new(vec![]));
+        Self {
+            path: dir.join(util::org_file_name(org_id, "rhai")),
+            engine: engine(config, operations.clone()),
+            operations,
+            script: None,
+            modified: None,
+        }
+    }
+
+    /// Reads the script when its file changed, or drops it when the file is gone. The source
+    /// still has to be passed to `reload`
+    async fn read_changes(&mut self) -> Option<String> {
+        let modified = match tokio::fs::metadata(&self.path).await {
+            Ok(metadata) => metadata.modified().ok(),
+            Err(_) => {
+                if self.modified.take().is_some() {
+                    self.script = None;
+                    info!(path = ?self.path, "Script removed");
+                }
+                return None;
+            }
+        };
+        if modified.is_some() && modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+
+        match tokio::fs::read_to_string(&self.path).await {
+            Ok(source) => Some(source),
+            Err(err) => {
+                self.script = None;
+                error!(path = ?self.path, error = ?err, "Failed to read script");
+                None
+            }
+        }
+    }
+
+    /// Replaces the script with `source`, a script that fails to load is dropped
+    fn reload(&mut self, source: &str) {
+        match self.load(source) {
+            Ok(script) => {
+                info!(path = ?self.path, "Loaded script");
+                self.script = Some(script);
+            }
+            Err(err) => {
+                self.script = None;
+                error!(
+                    path = ?self.path,
+                    error = %err,
+                    "Script failed to load, waiting for it to change"
+                );
+            }
+        }
+    }
+
+    /// Compiles `source` and runs its optional `init()`, whose result becomes `this`
+    fn load(&self, source: &str) -> Result<Script, Box<EvalAltResult>> {
+        let ast = self.engine.compile(source)?;
+        let has_init = ast
+            .iter_functions()
+            .any(|function| function.name == "init" && function.params.is_empty());
+        let state = match has_init {
+            true => self.engine.call_fn_with_options(
+                CallFnOptions::new().eval_ast(false),
+                &mut Scope::new(),
+                &ast,
+                "init",
+                (),
+            )?,
+            false => Map::new().into(),
+        };
+        Ok(Script { ast, state })
+    }
+
+    /// Calls the script's `tick(scene, dt)` and returns the operations it asked for. A script
+    /// that fails is stopped until its file changes, so a broken script is reported once.
+    fn tick(&mut self, scene: &Scene, dt: Duration) -> Vec<Operation> {
+        let Some(script) = &mut self.script else {
+            return vec![];
+        };
+        let mut scene_map = Map::new();
+        scene_map.insert("name".into(), scene.name.clone().into());
+        scene_map.insert(
+            "items".into(),
+            scene
+                .items
+                .iter()
+                .map(item_to_dynamic)
+                .collect::<Array>()
+                .into(),
+        );
+
+        self.operations.lock().unwrap().clear();
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            CallFnOptions::new()
+                .eval_ast(false)
+                .bind_this_ptr(&mut script.state),
+            &mut Scope::new(),
+            &script.ast,
+            "tick",
+            (scene_map, dt.as_secs_f64()),
+        );
+        let operations = std::mem::take(&mut *self.operations.lock().unwrap());
+        match result {
+            Ok(_) => operations,
+            Err(err) => {
+                self.script = None;
+                error!(
+                    path = ?self.path,
+                    error = %err,
+                    "Script failed, waiting for it to change"
+                );
+                vec![]
+            }
+        }
+    }
+}
+
+/// A sandboxed engine whose `spawn_item`, `update_item` and `despawn_item` queue operations
+fn engine(config: &ScriptConfig, operations: Arc<Mutex<Vec<Operation>>>) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .set_max_operations(config.max_operations)
+        .set_max_call_levels(config.max_call_depth)
+        .set_max_string_size(config.max_string_size)
+        .set_max_array_size(config.max_collection_size)
+        .set_max_map_size(config.max_collection_size);
+
+    // Runs inside the org's script task, whose span carries the org id
+    engine.on_print(|text| info!("{}", text));
+    engine.on_debug(|text, _, position| debug!(%position, "{}", text));
+
+    let spawned = operations.clone();
+    engine.register_fn(
+        "spawn_item",
+        move |item: Map| -> Result<(), Box<EvalAltResult>> {
+            let item = spawned_item(item)?;
+            spawned.lock().unwrap().push(Operation::Spawn(item));
+            Ok(())
+        },
+    );
+    let updated = operations.clone();
+    engine.register_fn(
+        "update_item",
+        move |id: ImmutableString, changes: Map| -> Result<(), Box<EvalAltResult>> {
+            let update = item_update(id.to_string(), changes)?;
+            updated.lock().unwrap().push(Operation::Update(update));
+            Ok(())
+        },
+    );
+    engine.register_fn("despawn_item", move |id: ImmutableString| {
+        operations
+            .lock()
+            .unwrap()
+            .push(Operation::Update(SceneUpdate::despawn(id.to_string())));
+    });
+    engine
+}
+
+fn item_to_dynamic(item: &SceneItem) -> Dynamic {
+    let mut map = Map::new();
+    map.insert("id".into(), item.id.clone().into());
+    map.insert("meshType".into(), format!("{:?}", item.mesh_type).into());
+    map.insert("position".into(), vector_to_dynamic(item.position));
+    map.insert("rotation".into(), vector_to_dynamic(item.rotation));
+    map.insert("color".into(), String::from(&item.color).into());
+    map.insert("scale".into(), vector_to_dynamic(item.scale));
+    map.into()
+}
+
+fn vector_to_dynamic((x, y, z): (f32, f32, f32)) -> Dynamic {
+    [x, y, z]
+        .into_iter()
+        .map(|value| Dynamic::from_float(value as f64))
+        .collect::<Array>()
+        .into()
+}
+
+/// `[x, y, z]`, integers are accepted so scripts can write `[0, 1, 0]`
+fn vector(key: &str, value: Dynamic) -> Result<(f32, f32, f32), String> {
+    let expected = || format!("{} must be an array of three finite numbers", key);
+    let array = value.try_cast::<Array>().ok_or_else(expected)?;
+    let numbers = array
+        .iter()
+        .map(|value| {
+            value
+                .as_float()
+                .or_else(|_| value.as_int().map(|value| value as f64))
+                .ok()
+                .map(|value| value as f32)
+                .filter(|value| value.is_finite())
+        })
+        .collect::<Option<Vec<f32>>>();
+    match numbers.as_deref() {
+        Some(&[x, y, z]) => Ok((x, y, z)),
+        _ => Err(expected()),
+    }
+}
+
+fn color(key: &str, value: Dynamic) -> Result<Color, String> {
+    value
+        .into_immutable_string()
+        .ok()
+        .and_then(|hex| Color::from_hex(&hex).ok())
+        .ok_or_else(|| format!("{} must be a color like \"#ff0000\"", key))
+}
+
+fn mesh_type(key: &str, value: Dynamic) -> Result<MeshType, String> {
+    match value.into_immutable_string().as_deref() {
+        Ok("Cube") => Ok(MeshType::Cube),
+        Ok("Sphere") => Ok(MeshType::Sphere),
+        Ok("Cylinder") => Ok(MeshType::Cylinder),
+        Ok("Plane") => Ok(MeshType::Plane),
+        _ => Err(format!(
+            "{} must be one of Cube, Sphere, Cylinder or Plane",
+            key
+        )),
+    }
+}
+
+fn field<T>(
+    map: &mut Map,
+    key: &str,
+    parse: fn(&str, Dynamic) -> Result<T, String>,
+) -> Result<Option<T>, String> {
+    map.remove(key).map(|value| parse(key, value)).transpose()
+}
+
+/// Keys a script misspelled would otherwise be ignored without a trace
+fn reject_unknown_keys(map: Map, function: &str) -> Result<(), String> {
+    match map.keys().next() {
+        Some(key) => Err(format!("{} does not take {:?}", function, key.as_str())),
+        None => Ok(()),
+    }
+}
+
+/// `spawn_item(#{ id, meshType, position, rotation, color, scale })`, only the id is required
+fn spawned_item(mut map: Map) -> Result<SceneItem, String> {
+    let id = map
+        .remove("id")
+        .and_then(|id| id.into_immutable_string().ok())
+        .ok_or("spawn_item needs a string id")?
+        .to_string();
+    let item = SceneItem {
+        id,
+        mesh_type: field(&mut map, "meshType", mesh_type)?.unwrap_or(MeshType::Cube),
+        position: field(&mut map, "position", vector)?.unwrap_or_default(),
+        rotation: field(&mut map, "rotation", vector)?.unwrap_or_default(),
+        color: field(&mut map, "color", color)?.unwrap_or(Color::white()),
+        scale: field(&mut map, "scale", vector)?.unwrap_or(scene::unit_scale()),
+    };
+    reject_unknown_keys(map, "spawn_item")?;
+    Ok(item)
+}
+
+/// `update_item(id, #{ position, rotation, color })`, every field is optional
+fn item_update(id: String, mut map: Map) -> Result<SceneUpdate, String> {
+    let update = SceneUpdate {
+        id,
+        position: field(&mut map, "position", vector)?,
+        rotation: field(&mut map, "rotation", vector)?,
+        color: field(&mut map, "color", color)?,
+        despawned: false,
+        mesh_type: None,
+        scale: None,
+    };
+    reject_unknown_keys(map, "update_item")?;
+    Ok(update)
+}
+
+/// Applies what the script asked for to the scene and returns the updates to broadcast.
+/// Items claimed by a game server are left to it, like the simulation does.
+fn apply_operations(
+    org: &mut Org,
+    operations: Vec<Operation>,
+    validation: &ValidationConfig,
+) -> Vec<SceneUpdate> {
+    let mut updates = vec![];
+    for operation in operations {
+        let (update, spawned) = match operation {
+            Operation::Spawn(item) => (
+                SceneUpdate {
+                    id: item.id.clone(),
+                    position: Some(item.position),
+                    rotation: Some(item.rotation),
+                    color: Some(item.color),
+                    despawned: false,
+                    mesh_type: Some(item.mesh_type.clone()),
+                    scale: Some(item.scale),
+                },
+                Some(item),
+            ),
+            Operation::Update(update) => (update, None),
+        };
+        let exists = org.scene.items.iter().any(|item| item.id == update.id);
+        let rejected = if spawned.is_some() && exists {
+            Some("item is already in the scene")
+        } else if org.owner_of(&update.id).is_some() {
+            Some("item is claimed by a game server")
+        } else {
+            match validation.validate(&update, &org.scene) {
+                Err(InvalidUpdate::UnknownItem) if spawned.is_some() => None,
+                result => result.err().map(|err| err.message()),
+            }
+        };
+        if let Some(reason) = rejected {
+            debug!(item_id = update.id, reason, "Dropped script operation");
+            continue;
+        }
+
+        if let Some(item) = spawned {
+            org.scene.items.push(item);
+        }
+        updates.push(update);
+    }
+    updates
+}
+
+/// The org's script runner, dropped with the org which stops it
+#[derive(Debug)]
+pub struct ScriptHandle {
+    task: AbortHandle,
+}
+
+impl ScriptHandle {
+    /// Starts watching `<dir>/<org id>.rhai`, the org does not need a script yet
+    pub fn spawn(org_id: &str, dir: &Path, state: &SharedState) -> Self {
+        let runner = ScriptRunner::new(org_id, dir, &state.scripts);
+        let task = tokio::spawn(script_task(org_id.to_string(), runner, state.clone()));
+        Self {
+            task: task.abort_handle(),
+        }
+    }
+}
+
+impl Drop for ScriptHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Runs Rhai on the blocking pool, evaluating a script is synchronous and would stall a runtime
+/// worker for as long as it takes. The runner is lost if `run` panics
+async fn run_blocking<T: Send + 'static>(
+    mut runner: ScriptRunner,
+    run: impl FnOnce(&mut ScriptRunner) -> T + Send + 'static,
+) -> Option<(ScriptRunner, T)> {
+    task::spawn_blocking(move || {
+        let result = run(&mut runner);
+        (runner, result)
+    })
+    .await
+    .inspect_err(|err| error!(error = %err, "Script runner failed, stopping it"))
+    .ok()
+}
+
+/// Ticks the org's script at the simulation interval and reloads it when it changes. Loading
+/// and ticking block, so they run on the blocking pool and without the org lock, a slow script
+/// holds up neither the runtime nor other orgs.
+#[instrument(skip(state, runner))]
+async fn script_task(org_id: String, mut runner: ScriptRunner, state: SharedState) {
+    let dt = state.throttle.simulation_interval;
+    let mut ticker = interval(dt);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut reloader = interval(state.scripts.reload_interval);
+    reloader.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = reloader.tick() => {
+                let Some(source) = runner.read_changes().await else {
+                    continue;
+                };
+                let Some((reloaded, ())) =
+                    run_blocking(runner, move |runner| runner.reload(&source)).await
+                else {
+                    return;
+                };
+                runner = reloaded;
+                continue;
+            }
+            _ = ticker.tick(), if runner.script.is_some() => {}
+        }
+
+        let scene = match state.orgs.lock().await.get(&org_id) {
+            Some(org) => org.scene.clone(),
+            None => return,
+        };
+        let Some((ticked, operations)) =
+            run_blocking(runner, move |runner| runner.tick(&scene, dt)).await
+        else {
+            return;
+        };
+        runner = ticked;
+        if operations.is_empty() {
+            continue;
+        }
+
+        let mut current_orgs = state.orgs.lock().await;
+        let Some(org) = current_orgs.get_mut(&org_id) else {
+            return;
+        };
+        let updates = apply_operations(org, operations, &state.validation);
+        if !updates.is_empty() {
+            org.broadcast(updates);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        org::{DisconnectPolicy, GameServer, ItemOwnership},
+        scene::{create_test_scene, test_update},
+    };
+
+    use super::*;
+
+    const DT: Duration = Duration::from_millis(50);
+
+    fn runner(source: &str) -> ScriptRunner {
+        let config = ScriptConfig {
+            max_operations: 10_000,
+            max_call_depth: 8,
+            max_string_size: 100,
+            max_collection_size: 100,
+            ..ScriptConfig::default()
+        };
+        let mut runner = ScriptRunner::new("org", Path::new("/nonexistent"), &config);
+        runner.reload(source);
+        runner
+    }
+
+    fn eval_error(source: &str) -> EvalAltResult {
+        *runner("")
+            .engine
+            .run(source)
+            .expect_err("script should fail")
+    }
+
+    #[test]
+    fn runaway_loop_stops_at_operations_limit() {
+        assert!(matches!(
+            eval_error("loop {}"),
+            EvalAltResult::ErrorTooManyOperations(_)
+        ));
+
+        let mut runner = runner("fn tick(scene, dt) { loop {} }");
+        assert!(runner.script.is_some());
+        assert!(runner.tick(&create_test_scene(), DT).is_empty());
+        assert!(runner.script.is_none());
+    }
+
+    #[test]
+    fn deep_recursion_is_rejected() {
+        assert!(matches!(
+            eval_error("fn down(n) { down(n + 1) } down(0);"),
+            EvalAltResult::ErrorStackOverflow(_)
+        ));
+    }
+
+    #[test]
+    fn strings_and_collections_are_limited() {
+        assert!(matches!(
+            eval_error(r#"let text = "a"; loop { text += text; }"#),
+            EvalAltResult::ErrorDataTooLarge(..)
+        ));
+        assert!(matches!(
+            eval_error("let items = []; loop { items.push(1); }"),
+            EvalAltResult::ErrorDataTooLarge(..)
+        ));
+    }
+
+    #[test]
+    fn import_is_blocked() {
+        assert!(matches!(
+            eval_error(r#"import "std" as std;"#),
+            EvalAltResult::ErrorModuleNotFound(..)
+        ));
+
+        let mut runner = runner(r#"fn tick(scene, dt) { import "std" as std; }"#);
+        assert!(runner.tick(&create_test_scene(), DT).is_empty());
+        assert!(runner.script.is_none());
+    }
+
+    #[test]
+    fn queues_operations_with_their_fields() {
+        let mut runner = runner(
+            r##"
+            fn init() { #{ ticks: 0 } }
+            fn tick(scene, dt) {
+                this.ticks += 1;
+                spawn_item(#{ id: "new", meshType: "Sphere", position: [0, 1.5, 0], scale: [2, 2, 2] });
+                update_item(scene.items[0].id, #{ color: "#00ff00", rotation: [0, this.ticks, 0] });
+                despawn_item("1");
+            }
+            "##,
+        );
+        runner.tick(&create_test_scene(), DT);
+        let operations = runner.tick(&create_test_scene(), DT);
+        let [Operation::Spawn(item), Operation::Update(update), Operation::Update(despawn)] =
+            &operations[..]
+        else {
+            panic!("unexpected operations {:?}", operations);
+        };
+        assert!(matches!(item.mesh_type, MeshType::Sphere));
+        assert_eq!(item.position, (0.0, 1.5, 0.0));
+        assert_eq!(item.scale, (2.0, 2.0, 2.0));
+        assert_eq!(update.id, "0");
+        assert_eq!(update.rotation, Some((0.0, 2.0, 0.0)));
+        assert!(update.color.is_some());
+        assert!(despawn.despawned);
+    }
+
+    #[test]
+    fn invalid_operations_stop_the_script() {
+        for source in [
+            r#"fn tick(scene, dt) { update_item("0", #{ postion: [0, 0, 0] }); }"#,
+            r#"fn tick(scene, dt) { update_item("0", #{ position: [0, 0] }); }"#,
+            r#"fn tick(scene, dt) { spawn_item(#{ meshType: "Cube" }); }"#,
+            r#"fn tick(scene, dt) { spawn_item(#{ id: "a", meshType: "Cone" }); }"#,
+        ] {
+            let mut runner = runner(source);
+            assert!(
+                runner.tick(&create_test_scene(), DT).is_empty(),
+                "{}",
+                source
+            );
+            assert!(runner.script.is_none(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn writes_to_claimed_items_are_dropped() {
+        let mut org = Org::new("org".into());
+        org.game_servers.push(GameServer::new(
+            0,
+            ItemOwnership::new(Some("0"), None),
+            DisconnectPolicy::Release,
+        ));
+        let move_to = |id: &str| {
+            let mut update = test_update(id);
+            update.position = Some((1.0, 1.0, 1.0));
+            Operation::Update(update)
+        };
+        let updates = apply_operations(
+            &mut org,
+            vec![move_to("0"), move_to("1")],
+            &ValidationConfig::default(),
+        );
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].id, "1");
+    }
+}
@@ -0,0 +1,118 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use axum::extract::ws::Message;
+use tokio::{
+    sync::watch,
+    time::{sleep, timeout},
+};
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    client_socket::ClientMessage,
+    disconnect::DisconnectReason,
+    game_socket::{queue_pending_updates, send_tick},
+    org::{GameServer, Org},
+    SharedState,
+};
+
+const DRAIN_POLL_INTERVAL_MS: u64 = 50;
+
+/// Resolves once the process receives SIGINT or SIGTERM
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install SIGINT handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
+
+/// Resolves once a shutdown signal arrived and the unready delay passed, `/readyz` reports not
+/// ready during the delay while the listener keeps accepting
+pub async fn stop_accepting(state: SharedState) {
+    signal().await;
+    state.accepting.store(false, Ordering::Relaxed);
+    info!(
+        delay_ms = state.lifecycle.unready_delay.as_millis() as u64,
+        "Reporting not ready before closing the listener"
+    );
+    sleep(state.lifecycle.unready_delay).await;
+}
+
+/// Resolves once the relay started shutting down
+pub async fn wait_for_shutdown(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown
+        .wait_for(|is_shutting_down| *is_shutting_down)
+        .await;
+}
+
+/// Closes every game server, then flushes pending batches, persists scenes and closes every
+/// viewer, then waits for their sockets to finish. Callers bound this with the shutdown
+/// deadline, game servers get half of it so scenes are still persisted when one hangs.
+#[instrument(skip(state))]
+pub async fn drain(state: SharedState) {
+    // Game sockets watch this, commit their open tick and close themselves. Their pending
+    // updates stay with the session
+    state.shutdown.send_replace(true);
+    let game_servers_closed = wait_until(&state, |org| {
+        org.game_servers.iter().all(GameServer::is_detached)
+    });
+    match timeout(state.lifecycle.shutdown_deadline / 2, game_servers_closed).await {
+        Ok(()) => info!("All game servers closed"),
+        Err(_) => warn!("Game servers did not close in time, persisting what they sent"),
+    }
+
+    let reconnect_in_ms = state.lifecycle.reconnect_delay.as_millis() as u64;
+    let restarting = serde_json::to_string(&ClientMessage::Restarting { reconnect_in_ms })
+        .expect("Failed to serialize message");
+
+    let mut current_orgs = state.orgs.lock().await;
+    for org in current_orgs.values_mut() {
+        queue_pending_updates(org).await;
+        send_tick(org, &state.metrics).await;
+
+        if let Some(storage) = &state.storage {
+            match storage.save(&org.id, &org.scene).await {
+                Ok(()) => info!(org_id = org.id, "Persisted scene"),
+                Err(err) => error!(org_id = org.id, error = ?err, "Failed to persist scene"),
+            }
+        }
+
+        for client in org.clients.iter() {
+            // The client's message task sends both in order and removes the client after the close frame
+            let _ = client.send(Message::Text(restarting.clone()));
+            let _ = client.send(DisconnectReason::ServerRestarting.close_message());
+        }
+        info!(
+            org_id = org.id,
+            client_count = org.clients.len(),
+            "Notified viewers of shutdown"
+        );
+    }
+    drop(current_orgs);
+
+    wait_until(&state, |org| org.clients.is_empty()).await;
+    info!("All connections drained");
+}
+
+/// Resolves once `is_drained` holds for every org
+async fn wait_until(state: &SharedState, is_drained: impl Fn(&Org) -> bool) {
+    while !state.orgs.lock().await.values().all(&is_drained) {
+        sleep(Duration::from_millis(DRAIN_POLL_INTERVAL_MS)).await;
+    }
+}
@@ -0,0 +1,147 @@
+use std::f32::consts::TAU;
+
+use rand::{rngs::StdRng, Rng};
+
+use crate::{data::color::Color, scene::SceneItem};
+
+use super::{Registry, Simulation};
+
+/// How fast a wandering item may turn, in radians per second
+const WANDER_TURN_RATE: f32 = 3.0;
+
+pub fn register(registry: &mut Registry) {
+    registry.register("spin", &["speed"], |params| {
+        Box::new(Spin {
+            speed: params.get("speed", 1.0),
+        })
+    });
+    registry.register("orbit", &["radius", "speed"], |params| {
+        Box::new(Orbit {
+            radius: params.get("radius", 2.0),
+            speed: params.get("speed", 1.0),
+            centre: None,
+            angle: 0.0,
+        })
+    });
+    registry.register("bounce", &["height", "speed"], |params| {
+        Box::new(Bounce {
+            height: params.get("height", 1.0),
+            speed: params.get("speed", 3.0),
+            base: None,
+            phase: 0.0,
+        })
+    });
+    registry.register("color_cycle", &["speed"], |params| {
+        Box::new(ColorCycle {
+            speed: params.get("speed", 30.0),
+            hsv: None,
+        })
+    });
+    registry.register("wander", &["speed", "radius"], |params| {
+        Box::new(Wander {
+            speed: params.get("speed", 1.0),
+            radius: params.get("radius", 5.0),
+            origin: None,
+            heading: 0.0,
+        })
+    });
+}
+
+/// Turns around the y axis, `speed` in radians per second
+#[derive(Debug)]
+struct Spin {
+    speed: f32,
+}
+
+impl Simulation for Spin {
+    fn step(&mut self, item: &mut SceneItem, dt: f32, _rng: &mut StdRng) {
+        item.rotation.1 = (item.rotation.1 + self.speed * dt).rem_euclid(TAU);
+    }
+}
+
+/// Circles in the xz plane, starting where the item is, `speed` in radians per second
+#[derive(Debug)]
+struct Orbit {
+    radius: f32,
+    speed: f32,
+    centre: Option<(f32, f32)>,
+    angle: f32,
+}
+
+impl Simulation for Orbit {
+    fn step(&mut self, item: &mut SceneItem, dt: f32, _rng: &mut StdRng) {
+        let (x, _, z) = item.position;
+        let (centre_x, centre_z) = *self.centre.get_or_insert((x - self.radius, z));
+        self.angle = (self.angle + self.speed * dt).rem_euclid(TAU);
+        item.position.0 = centre_x + self.radius * self.angle.cos();
+        item.position.2 = centre_z + self.radius * self.angle.sin();
+    }
+}
+
+/// Hops up to `height` above where the item started, one hop takes π / `speed` seconds
+#[derive(Debug)]
+struct Bounce {
+    height: f32,
+    speed: f32,
+    base: Option<f32>,
+    phase: f32,
+}
+
+impl Simulation for Bounce {
+    fn step(&mut self, item: &mut SceneItem, dt: f32, _rng: &mut StdRng) {
+        let base = *self.base.get_or_insert(item.position.1);
+        self.phase = (self.phase + self.speed * dt).rem_euclid(TAU);
+        item.position.1 = base + self.height * self.phase.sin().abs();
+    }
+}
+
+/// Rotates the hue, `speed` in degrees per second. The hue is kept as a float so slow cycles do
+/// not stall on the 8 bit channels.
+#[derive(Debug)]
+struct ColorCycle {
+    speed: f32,
+    hsv: Option<(f32, f32, f32)>,
+}
+
+impl Simulation for ColorCycle {
+    fn step(&mut self, item: &mut SceneItem, dt: f32, _rng: &mut StdRng) {
+        let (hue, saturation, value) = self.hsv.get_or_insert_with(|| {
+            let (hue, saturation, value) = item.color.to_hsv();
+            // Grey items have no hue to rotate, give them one
+            match saturation == 0.0 {
+                true => (hue, 1.0, value.max(0.5)),
+                false => (hue, saturation, value),
+            }
+        });
+        *hue = (*hue + self.speed * dt).rem_euclid(360.0);
+        item.color = Color::from_hsv(*hue, *saturation, *value);
+    }
+}
+
+/// Drifts in the xz plane at `speed` units per second, turning back when it strays more than
+/// `radius` from where it started
+#[derive(Debug)]
+struct Wander {
+    speed: f32,
+    radius: f32,
+    origin: Option<(f32, f32)>,
+    heading: f32,
+}
+
+impl Simulation for Wander {
+    fn step(&mut self, item: &mut SceneItem, dt: f32, rng: &mut StdRng) {
+        let (x, _, z) = item.position;
+        let (origin_x, origin_z) = *self.origin.get_or_insert_with(|| {
+            self.heading = rng.gen_range(0.0..TAU);
+            (x, z)
+        });
+        let (from_origin_x, from_origin_z) = (x - origin_x, z - origin_z);
+        if from_origin_x.hypot(from_origin_z) > self.radius {
+            self.heading = (-from_origin_z).atan2(-from_origin_x);
+        } else {
+            self.heading += rng.gen_range(-1.0..=1.0) * WANDER_TURN_RATE * dt;
+        }
+        item.position.0 += self.heading.cos() * self.speed * dt;
+        item.position.2 += self.heading.sin() * self.speed * dt;
+    }
+}
@@ -0,0 +1,160 @@
+use std::ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Vec3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Vec3 {
+    pub const ZERO: Vec3 = Vec3::new(0.0, 0.0, 0.0);
+    pub const UP: Vec3 = Vec3::new(0.0, 1.0, 0.0);
+
+    pub const fn new(x: f32, y: f32, z: f32) -> Self {
+        Self { x, y, z }
+    }
+
+    pub fn dot(self, other: Vec3) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn cross(self, other: Vec3) -> Vec3 {
+        Vec3::new(
+            self.y * other.z - self.z * other.y,
+            self.z * other.x - self.x * other.z,
+            self.x * other.y - self.y * other.x,
+        )
+    }
+
+    pub fn length(self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    pub fn max_element(self) -> f32 {
+        self.x.max(self.y).max(self.z)
+    }
+}
+
+impl From<(f32, f32, f32)> for Vec3 {
+    fn from((x, y, z): (f32, f32, f32)) -> Self {
+        Vec3::new(x, y, z)
+    }
+}
+
+impl From<Vec3> for (f32, f32, f32) {
+    fn from(v: Vec3) -> Self {
+        (v.x, v.y, v.z)
+    }
+}
+
+impl Add for Vec3 {
+    type Output = Vec3;
+
+    fn add(self, other: Vec3) -> Vec3 {
+        Vec3::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+}
+
+impl AddAssign for Vec3 {
+    fn add_assign(&mut self, other: Vec3) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Vec3 {
+    type Output = Vec3;
+
+    fn sub(self, other: Vec3) -> Vec3 {
+        Vec3::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+impl SubAssign for Vec3 {
+    fn sub_assign(&mut self, other: Vec3) {
+        *self = *self - other;
+    }
+}
+
+impl Mul<f32> for Vec3 {
+    type Output = Vec3;
+
+    fn mul(self, scale: f32) -> Vec3 {
+        Vec3::new(self.x * scale, self.y * scale, self.z * scale)
+    }
+}
+
+impl Neg for Vec3 {
+    type Output = Vec3;
+
+    fn neg(self) -> Vec3 {
+        self * -1.0
+    }
+}
+
+/// Unit quaternion for orientations, converted from and to the XYZ euler angles items carry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quat {
+    pub w: f32,
+    pub v: Vec3,
+}
+
+impl Quat {
+    /// Same convention as three.js, which the viewers render with
+    pub fn from_euler_xyz((x, y, z): (f32, f32, f32)) -> Self {
+        let (s1, c1) = (x / 2.0).sin_cos();
+        let (s2, c2) = (y / 2.0).sin_cos();
+        let (s3, c3) = (z / 2.0).sin_cos();
+        Self {
+            w: c1 * c2 * c3 - s1 * s2 * s3,
+            v: Vec3::new(
+                s1 * c2 * c3 + c1 * s2 * s3,
+                c1 * s2 * c3 - s1 * c2 * s3,
+                c1 * c2 * s3 + s1 * s2 * c3,
+            ),
+        }
+    }
+
+    pub fn to_euler_xyz(self) -> (f32, f32, f32) {
+        let Vec3 { x, y, z } = self.v;
+        let w = self.w;
+        let m11 = 1.0 - 2.0 * (y * y + z * z);
+        let m12 = 2.0 * (x * y - w * z);
+        let m13 = 2.0 * (x * z + w * y);
+        let m22 = 1.0 - 2.0 * (x * x + z * z);
+        let m23 = 2.0 * (y * z - w * x);
+        let m32 = 2.0 * (y * z + w * x);
+        let m33 = 1.0 - 2.0 * (x * x + y * y);
+        let euler_y = m13.clamp(-1.0, 1.0).asin();
+        match m13.abs() < 0.999_999 {
+            true => ((-m23).atan2(m33), euler_y, (-m12).atan2(m11)),
+            false => (m32.atan2(m22), euler_y, 0.0),
+        }
+    }
+
+    pub fn rotate(self, point: Vec3) -> Vec3 {
+        let t = self.v.cross(point) * 2.0;
+        point + t * self.w + self.v.cross(t)
+    }
+
+    /// Turns by `angular_velocity`, in radians per second around world axes, for `dt` seconds
+    pub fn integrate(self, angular_velocity: Vec3, dt: f32) -> Self {
+        let spin = Quat {
+            w: -angular_velocity.dot(self.v),
+            v: angular_velocity * self.w + angular_velocity.cross(self.v),
+        };
+        Quat {
+            w: self.w + spin.w * dt / 2.0,
+            v: self.v + spin.v * (dt / 2.0),
+        }
+        .normalized()
+    }
+
+    fn normalized(self) -> Self {
+        let length = (self.w * self.w + self.v.dot(self.v)).sqrt();
+        Quat {
+            w: self.w / length,
+            v: self.v * (1.0 / length),
+        }
+    }
+}
@@ -0,0 +1,468 @@
+use std::{collections::HashMap, fmt, str::FromStr, time::Duration};
+
+use rand::{rngs::StdRng, SeedableRng};
+use serde::Deserialize;
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::AbortHandle,
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{info, instrument};
+
+use crate::{
+    error::RelayError,
+    org::Org,
+    scene::{SceneItem, SceneUpdate},
+    validation::WorldBounds,
+    SharedState,
+};
+
+use physics::{Impulse, PhysicsConfig, PhysicsWorld};
+
+mod behaviours;
+mod math;
+pub mod physics;
+
+/// Drives one scene item, stepped by the relay at the simulation interval
+pub trait Simulation: Send + fmt::Debug {
+    /// Advances `item` by `dt` seconds, only randomness drawn from `rng` keeps runs repeatable
+    fn step(&mut self, item: &mut SceneItem, dt: f32, rng: &mut StdRng);
+}
+
+/// Numeric parameters of a behaviour, e.g. `radius` for `orbit`
+#[derive(Debug, Clone, Copy)]
+pub struct Params<'a>(&'a HashMap<String, f32>);
+
+impl Params<'_> {
+    pub fn get(&self, name: &str, default: f32) -> f32 {
+        self.0.get(name).copied().unwrap_or(default)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Behaviour {
+    params: &'static [&'static str],
+    create: fn(Params) -> Box<dyn Simulation>,
+}
+
+/// Behaviours items can be given by name in the config
+#[derive(Clone)]
+pub struct Registry {
+    behaviours: HashMap<&'static str, Behaviour>,
+}
+
+impl Registry {
+    pub fn built_in() -> Self {
+        let mut registry = Self {
+            behaviours: HashMap::new(),
+        };
+        behaviours::register(&mut registry);
+        registry
+    }
+
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        params: &'static [&'static str],
+        create: fn(Params) -> Box<dyn Simulation>,
+    ) {
+        self.behaviours.insert(name, Behaviour { params, create });
+    }
+
+    pub fn create(&self, config: &BehaviourConfig) -> Result<Box<dyn Simulation>, String> {
+        let behaviour = self.behaviours.get(config.name.as_str()).ok_or_else(|| {
+            let mut names = self.behaviours.keys().copied().collect::<Vec<&str>>();
+            names.sort();
+            format!(
+                "unknown behaviour {:?}, expected one of {}",
+                config.name,
+                names.join(", ")
+            )
+        })?;
+        if let Some(param) = config
+            .params
+            .keys()
+            .find(|param| !behaviour.params.contains(&param.as_str()))
+        {
+            return Err(format!(
+                "{} has no parameter {:?}, expected one of {}",
+                config.name,
+                param,
+                behaviour.params.join(", ")
+            ));
+        }
+        Ok((behaviour.create)(Params(&config.params)))
+    }
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.behaviours.keys()).finish()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BehaviourConfig {
+    pub name: String,
+    #[serde(default)]
+    pub params: HashMap<String, f32>,
+}
+
+/// A bare behaviour name with default parameters, the format of `SIMULATION_BEHAVIOURS`
+impl FromStr for BehaviourConfig {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            name: name.to_string(),
+            params: HashMap::new(),
+        })
+    }
+}
+
+/// Whether game servers can still connect while the relay simulates an org
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SimulationMode {
+    /// Items claimed by a connected game server are left to it, the rest are simulated
+    #[default]
+    Alongside,
+    /// Game servers are turned away
+    Exclusive,
+}
+
+impl FromStr for SimulationMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "alongside" => Ok(SimulationMode::Alongside),
+            "exclusive" => Ok(SimulationMode::Exclusive),
+            _ => Err("expected alongside or exclusive".into()),
+        }
+    }
+}
+
+/// How the relay moves simulated items
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EngineKind {
+    /// Each item runs its configured behaviours
+    #[default]
+    Behaviours,
+    /// Items fall, collide and take impulses, behaviours are not used
+    Physics,
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "behaviours" => Ok(EngineKind::Behaviours),
+            "physics" => Ok(EngineKind::Physics),
+            _ => Err("expected behaviours or physics".into()),
+        }
+    }
+}
+
+/// Built in simulation, run for every org while `server.simulate` is on
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulationConfig {
+    /// The same seed replays the same simulation, a random one is picked and logged when unset
+    pub seed: Option<u64>,
+    pub mode: SimulationMode,
+    pub engine: EngineKind,
+    pub physics: PhysicsConfig,
+    /// Behaviours of items without their own
+    pub behaviours: Vec<BehaviourConfig>,
+    pub orgs: HashMap<String, OrgSimulationConfig>,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            seed: None,
+            mode: SimulationMode::default(),
+            engine: EngineKind::default(),
+            physics: PhysicsConfig::default(),
+            behaviours: ["spin", "color_cycle"]
+                .into_iter()
+                .map(|name| name.parse().expect("behaviour names always parse"))
+                .collect(),
+            orgs: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct OrgSimulationConfig {
+    /// Replaces the default behaviours for the org's items
+    pub behaviours: Option<Vec<BehaviourConfig>>,
+    /// Behaviours of single items by id
+    pub items: HashMap<String, Vec<BehaviourConfig>>,
+}
+
+impl SimulationConfig {
+    fn behaviours_for(&self, org_id: &str, item_id: &str) -> &[BehaviourConfig] {
+        let org = self.orgs.get(org_id);
+        org.and_then(|org| org.items.get(item_id))
+            .or_else(|| org.and_then(|org| org.behaviours.as_ref()))
+            .unwrap_or(&self.behaviours)
+    }
+
+    /// Every behaviour the registry cannot create, with where it is configured
+    pub fn invalid_behaviours(&self, registry: &Registry) -> Vec<String> {
+        let orgs = self.orgs.iter().flat_map(|(org_id, org)| {
+            let org_behaviours = org.behaviours.iter().flatten().map(move |behaviour| {
+                (format!("simulation.orgs.{}.behaviours", org_id), behaviour)
+            });
+            let item_behaviours = org.items.iter().flat_map(move |(item_id, behaviours)| {
+                behaviours.iter().map(move |behaviour| {
+                    (
+                        format!("simulation.orgs.{}.items.{}", org_id, item_id),
+                        behaviour,
+                    )
+                })
+            });
+            org_behaviours.chain(item_behaviours)
+        });
+        self.behaviours
+            .iter()
+            .map(|behaviour| ("simulation.behaviours".to_string(), behaviour))
+            .chain(orgs)
+            .filter_map(|(path, behaviour)| {
+                registry
+                    .create(behaviour)
+                    .err()
+                    .map(|err| format!("{}: {}", path, err))
+            })
+            .collect()
+    }
+}
+
+/// Hashes the org id the same way on every run and platform, unlike `DefaultHasher`
+fn org_seed(seed: u64, org_id: &str) -> u64 {
+    org_id
+        .bytes()
+        .fold(seed ^ 0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// The simulation of one org, each item gets its behaviours the first time it is stepped
+#[derive(Debug)]
+pub struct SimulationEngine {
+    org_id: String,
+    config: SimulationConfig,
+    registry: Registry,
+    rng: StdRng,
+    items: HashMap<String, Vec<Box<dyn Simulation>>>,
+    physics: Option<PhysicsWorld>,
+    impulses: UnboundedReceiver<(String, Impulse)>,
+}
+
+impl SimulationEngine {
+    /// The relay fills in a random seed at startup when none is configured
+    fn new(
+        org_id: &str,
+        config: SimulationConfig,
+        world_bounds: Option<WorldBounds>,
+        impulses: UnboundedReceiver<(String, Impulse)>,
+    ) -> Self {
+        Self {
+            org_id: org_id.to_string(),
+            rng: StdRng::seed_from_u64(org_seed(config.seed.unwrap_or_default(), org_id)),
+            physics: (config.engine == EngineKind::Physics)
+                .then(|| PhysicsWorld::new(config.physics, world_bounds)),
+            config,
+            registry: Registry::built_in(),
+            items: HashMap::new(),
+            impulses,
+        }
+    }
+
+    /// Steps every simulated item by `dt` and returns what changed
+    pub fn step(&mut self, org: &Org, dt: Duration) -> Vec<SceneUpdate> {
+        let dt = dt.as_secs_f32();
+        let mut impulses = vec![];
+        while let Ok(impulse) = self.impulses.try_recv() {
+            impulses.push(impulse);
+        }
+        if let Some(physics) = &mut self.physics {
+            let simulated = org
+                .scene
+                .items
+                .iter()
+                .map(|item| is_simulated(org, item))
+                .collect::<Vec<bool>>();
+            let mut stepped = org.scene.items.clone();
+            physics.step(&mut stepped, &simulated, impulses, dt);
+            return org
+                .scene
+                .items
+                .iter()
+                .zip(&stepped)
+                .filter_map(|(item, stepped)| changes(item, stepped))
+                .collect();
+        }
+
+        // Despawned items take their behaviours with them, a respawn starts over
+        self.items
+            .retain(|id, _| org.scene.items.iter().any(|item| &item.id == id));
+        let mut updates = vec![];
+        for item in &org.scene.items {
+            if !is_simulated(org, item) {
+                continue;
+            }
+
+            let behaviours = self.items.entry(item.id.clone()).or_insert_with(|| {
+                self.config
+                    .behaviours_for(&self.org_id, &item.id)
+                    .iter()
+                    .map(|behaviour| {
+                        self.registry
+                            .create(behaviour)
+                            .expect("behaviours are checked when the config is loaded")
+                    })
+                    .collect()
+            });
+            let mut stepped = item.clone();
+            for behaviour in behaviours.iter_mut() {
+                behaviour.step(&mut stepped, dt, &mut self.rng);
+            }
+            if let Some(update) = changes(item, &stepped) {
+                updates.push(update);
+            }
+        }
+        updates
+    }
+}
+
+/// Items claimed by a game server are left to it, exclusive mode turns game servers away
+fn is_simulated(org: &Org, item: &SceneItem) -> bool {
+    org.owner_of(&item.id).is_none()
+}
+
+fn changes(before: &SceneItem, after: &SceneItem) -> Option<SceneUpdate> {
+    let update = SceneUpdate {
+        id: after.id.clone(),
+        position: (after.position != before.position).then_some(after.position),
+        rotation: (after.rotation != before.rotation).then_some(after.rotation),
+        color: (after.color != before.color).then_some(after.color),
+        despawned: false,
+        mesh_type: None,
+        scale: None,
+    };
+    (update.position.is_some() || update.rotation.is_some() || update.color.is_some())
+        .then_some(update)
+}
+
+/// The org's running simulation, dropped with the org which stops it
+#[derive(Debug)]
+pub struct SimulationHandle {
+    task: AbortHandle,
+    /// Only set for the physics engine, behaviours cannot be pushed
+    impulses: Option<UnboundedSender<(String, Impulse)>>,
+}
+
+impl SimulationHandle {
+    /// Starts simulating `org_id`, it has to be in the org map before the first step
+    pub fn spawn(org_id: &str, config: SimulationConfig, state: &SharedState) -> Self {
+        let (sender, receiver) = unbounded_channel();
+        let impulses = (config.engine == EngineKind::Physics).then_some(sender);
+        let engine = SimulationEngine::new(org_id, config, state.validation.world_bounds, receiver);
+        let task = tokio::spawn(simulation_task(org_id.to_string(), engine, state.clone()));
+        Self {
+            task: task.abort_handle(),
+            impulses,
+        }
+    }
+
+    /// Queues `impulse` for the next step
+    pub fn push_impulse(&self, item_id: &str, impulse: Impulse) -> Result<(), RelayError> {
+        self.impulses
+            .as_ref()
+            .ok_or(RelayError::PhysicsNotRunning)?
+            .send((item_id.to_string(), impulse))
+            .map_err(|_| RelayError::PhysicsNotRunning)
+    }
+}
+
+impl Drop for SimulationHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Steps the org's simulation at the simulation interval until its handle is dropped
+#[instrument(skip(state, engine))]
+async fn simulation_task(org_id: String, mut engine: SimulationEngine, state: SharedState) {
+    let dt = state.throttle.simulation_interval;
+    let mut ticker = interval(dt);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    info!("Simulation started");
+    loop {
+        ticker.tick().await;
+        let mut current_orgs = state.orgs.lock().await;
+        let Some(org) = current_orgs.get_mut(&org_id) else {
+            return;
+        };
+        let updates = engine.step(org, dt);
+        if !updates.is_empty() {
+            org.broadcast(updates);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(seed: u64) -> SimulationEngine {
+        let config = SimulationConfig {
+            seed: Some(seed),
+            behaviours: ["wander", "spin", "color_cycle"]
+                .into_iter()
+                .map(|name| name.parse().unwrap())
+                .collect(),
+            ..SimulationConfig::default()
+        };
+        SimulationEngine::new("org", config, None, unbounded_channel().1)
+    }
+
+    fn run(engine: &mut SimulationEngine, steps: usize) -> Vec<String> {
+        let mut org = Org::new("org".into());
+        (0..steps)
+            .map(|_| {
+                let updates = engine.step(&org, Duration::from_millis(50));
+                let json = serde_json::to_string(&updates).unwrap();
+                org.broadcast(updates);
+                json
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_replays_same_updates() {
+        let first = run(&mut engine(7), 100);
+        assert_eq!(first, run(&mut engine(7), 100));
+        assert_ne!(first, run(&mut engine(8), 100));
+    }
+
+    #[test]
+    fn despawned_items_drop_their_behaviours() {
+        let mut engine = engine(7);
+        let mut org = Org::new("org".into());
+        engine.step(&org, Duration::from_millis(50));
+        assert!(engine.items.contains_key("1"));
+
+        org.broadcast(vec![SceneUpdate::despawn("1".into())]);
+        engine.step(&org, Duration::from_millis(50));
+        assert!(!engine.items.contains_key("1"));
+        assert_eq!(engine.items.len(), org.scene.items.len());
+    }
+}
@@ -0,0 +1,551 @@
+use std::{collections::HashMap, f32::consts::PI};
+
+use serde::Deserialize;
+
+use crate::{
+    scene::{MeshType, SceneItem},
+    validation::WorldBounds,
+};
+
+use super::math::{Quat, Vec3};
+
+const DEFAULT_GRAVITY: f32 = -9.81;
+const DEFAULT_RESTITUTION: f32 = 0.3;
+const DEFAULT_FRICTION: f32 = 0.5;
+const DEFAULT_LINEAR_DAMPING: f32 = 0.05;
+const DEFAULT_ANGULAR_DAMPING: f32 = 0.3;
+const DEFAULT_GROUND_Y: f32 = 0.0;
+
+/// Contacts closing slower than this do not bounce, so resting bodies settle instead of jittering
+const BOUNCE_SPEED: f32 = 1.0;
+/// How far above the ground a point still counts as touching it
+const CONTACT_SLOP: f32 = 0.01;
+const SOLVER_ITERATIONS: usize = 4;
+/// Bodies slower than this, in units or radians per second, for `SLEEP_AFTER` seconds stop
+/// being stepped until something pushes them
+const SLEEP_SPEED: f32 = 0.05;
+const SLEEP_AFTER: f32 = 0.5;
+/// Keeps degenerate scales from producing infinite inverse masses
+const MIN_EXTENT: f32 = 0.01;
+
+/// Rigid body physics for `engine = "physics"`. Items are solid with a density of 1 and sized
+/// by their `scale`, `Plane` items are infinite horizontal ground at their height. Items are
+/// kept inside `validation.world_bounds` when it is set.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct PhysicsConfig {
+    /// Acceleration along y in units per second squared
+    pub gravity: f32,
+    /// Share of the speed kept when bouncing, between 0 and 1
+    pub restitution: f32,
+    pub friction: f32,
+    pub linear_damping: f32,
+    pub angular_damping: f32,
+    /// Height of the ground in scenes without a `Plane` item
+    pub ground_y: f32,
+}
+
+impl Default for PhysicsConfig {
+    fn default() -> Self {
+        Self {
+            gravity: DEFAULT_GRAVITY,
+            restitution: DEFAULT_RESTITUTION,
+            friction: DEFAULT_FRICTION,
+            linear_damping: DEFAULT_LINEAR_DAMPING,
+            angular_damping: DEFAULT_ANGULAR_DAMPING,
+            ground_y: DEFAULT_GROUND_Y,
+        }
+    }
+}
+
+/// A push given to one item, changing its velocity by `linear` and its spin by `angular`
+/// divided by its mass and inertia
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub struct Impulse {
+    #[serde(default)]
+    pub linear: (f32, f32, f32),
+    #[serde(default)]
+    pub angular: (f32, f32, f32),
+}
+
+impl Impulse {
+    pub fn is_finite(&self) -> bool {
+        let (linear, angular) = (self.linear, self.angular);
+        [
+            linear.0, linear.1, linear.2, angular.0, angular.1, angular.2,
+        ]
+        .iter()
+        .all(|value| value.is_finite())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Shape {
+    Sphere {
+        radius: f32,
+    },
+    /// Cylinders are boxes too, close enough for stacking and knocking things over
+    Box {
+        half_extents: Vec3,
+    },
+}
+
+impl Shape {
+    fn of(item: &SceneItem) -> Option<Self> {
+        let size = Vec3::from(item.scale);
+        let half = |extent: f32| (extent.abs() / 2.0).max(MIN_EXTENT);
+        match item.mesh_type {
+            MeshType::Plane => None,
+            MeshType::Sphere => Some(Shape::Sphere {
+                radius: half(size.max_element()),
+            }),
+            MeshType::Cube | MeshType::Cylinder => Some(Shape::Box {
+                half_extents: Vec3::new(half(size.x), half(size.y), half(size.z)),
+            }),
+        }
+    }
+
+    /// Other items are collided with as spheres of this radius
+    fn bounding_radius(&self) -> f32 {
+        match self {
+            Shape::Sphere { radius } => *radius,
+            Shape::Box { half_extents } => half_extents.length(),
+        }
+    }
+
+    /// Inverse mass and inverse inertia, the inertia is averaged over the axes
+    fn inverse_mass(&self) -> (f32, f32) {
+        match *self {
+            Shape::Sphere { radius } => {
+                let mass = 4.0 / 3.0 * PI * radius.powi(3);
+                (1.0 / mass, 1.0 / (0.4 * mass * radius * radius))
+            }
+            Shape::Box { half_extents: half } => {
+                let mass = 8.0 * half.x * half.y * half.z;
+                (1.0 / mass, 1.0 / (2.0 / 9.0 * mass * half.dot(half)))
+            }
+        }
+    }
+
+    /// Points that can touch the ground, relative to the centre
+    fn contact_points(&self, orientation: Quat) -> Vec<Vec3> {
+        match *self {
+            Shape::Sphere { radius } => vec![Vec3::UP * -radius],
+            Shape::Box { half_extents: half } => [-1.0, 1.0]
+                .into_iter()
+                .flat_map(|x| [-1.0, 1.0].into_iter().map(move |y| (x, y)))
+                .flat_map(|(x, y)| [-1.0, 1.0].into_iter().map(move |z| (x, y, z)))
+                .map(|(x, y, z)| orientation.rotate(Vec3::new(half.x * x, half.y * y, half.z * z)))
+                .collect(),
+        }
+    }
+}
+
+/// What the world remembers about an item between steps, its position is always read from the
+/// scene so moves made by game servers or the admin api are picked up
+#[derive(Debug, Clone, Copy)]
+struct Body {
+    velocity: Vec3,
+    angular_velocity: Vec3,
+    orientation: Quat,
+    /// The rotation last written to the item, the orientation is rebuilt when it changed
+    rotation: (f32, f32, f32),
+    resting_for: f32,
+    asleep: bool,
+}
+
+impl Body {
+    fn new(item: &SceneItem) -> Self {
+        Self {
+            velocity: Vec3::ZERO,
+            angular_velocity: Vec3::ZERO,
+            orientation: Quat::from_euler_xyz(item.rotation),
+            rotation: item.rotation,
+            resting_for: 0.0,
+            asleep: false,
+        }
+    }
+
+    fn wake(&mut self) {
+        self.asleep = false;
+        self.resting_for = 0.0;
+    }
+}
+
+/// A body taking part in one step. Items the world does not simulate are solids too, but
+/// static ones with an inverse mass of 0.
+#[derive(Debug)]
+struct Solid {
+    index: usize,
+    position: Vec3,
+    shape: Shape,
+    inverse_mass: f32,
+    inverse_inertia: f32,
+    body: Body,
+}
+
+impl Solid {
+    fn is_dynamic(&self) -> bool {
+        self.inverse_mass > 0.0
+    }
+
+    fn is_moving(&self) -> bool {
+        self.is_dynamic() && !self.body.asleep
+    }
+
+    fn apply_impulse(&mut self, impulse: Vec3, offset: Vec3) {
+        self.body.velocity += impulse * self.inverse_mass;
+        self.body.angular_velocity += offset.cross(impulse) * self.inverse_inertia;
+    }
+
+    fn point_velocity(&self, offset: Vec3) -> Vec3 {
+        self.body.velocity + self.body.angular_velocity.cross(offset)
+    }
+
+    /// Effective inverse mass of the body pushed at `offset` along `direction`
+    fn inverse_mass_along(&self, offset: Vec3, direction: Vec3) -> f32 {
+        let arm = offset.cross(direction);
+        self.inverse_mass + self.inverse_inertia * arm.dot(arm)
+    }
+}
+
+#[derive(Debug)]
+pub struct PhysicsWorld {
+    config: PhysicsConfig,
+    world_bounds: Option<WorldBounds>,
+    bodies: HashMap<String, Body>,
+}
+
+impl PhysicsWorld {
+    pub fn new(config: PhysicsConfig, world_bounds: Option<WorldBounds>) -> Self {
+        Self {
+            config,
+            world_bounds,
+            bodies: HashMap::new(),
+        }
+    }
+
+    /// Advances `items` by `dt` seconds, only items with `simulated` set move
+    pub fn step(
+        &mut self,
+        items: &mut [SceneItem],
+        simulated: &[bool],
+        impulses: Vec<(String, Impulse)>,
+        dt: f32,
+    ) {
+        self.bodies
+            .retain(|id, _| items.iter().any(|item| &item.id == id));
+
+        let mut grounds = items
+            .iter()
+            .filter(|item| matches!(item.mesh_type, MeshType::Plane))
+            .map(|item| item.position.1)
+            .collect::<Vec<f32>>();
+        if grounds.is_empty() {
+            grounds.push(self.config.ground_y);
+        }
+        let mut solids = items
+            .iter()
+            .enumerate()
+            .filter_map(|(index, item)| {
+                let shape = Shape::of(item)?;
+                let (inverse_mass, inverse_inertia, body) = match simulated[index] {
+                    true => {
+                        let (inverse_mass, inverse_inertia) = shape.inverse_mass();
+                        let mut body = *self
+                            .bodies
+                            .entry(item.id.clone())
+                            .or_insert_with(|| Body::new(item));
+                        if body.rotation != item.rotation {
+                            body.orientation = Quat::from_euler_xyz(item.rotation);
+                        }
+                        (inverse_mass, inverse_inertia, body)
+                    }
+                    false => (0.0, 0.0, Body::new(item)),
+                };
+                Some(Solid {
+                    index,
+                    position: item.position.into(),
+                    shape,
+                    inverse_mass,
+                    inverse_inertia,
+                    body,
+                })
+            })
+            .collect::<Vec<Solid>>();
+
+        for (id, impulse) in impulses {
+            if let Some(solid) = solids
+                .iter_mut()
+                .find(|solid| solid.is_dynamic() && items[solid.index].id == id)
+            {
+                solid.body.wake();
+                solid.body.velocity += Vec3::from(impulse.linear) * solid.inverse_mass;
+                solid.body.angular_velocity += Vec3::from(impulse.angular) * solid.inverse_inertia;
+            }
+        }
+
+        let config = self.config;
+        for solid in solids.iter_mut().filter(|solid| solid.is_moving()) {
+            let body = &mut solid.body;
+            body.velocity.y += config.gravity * dt;
+            body.velocity = body.velocity * (1.0 / (1.0 + config.linear_damping * dt));
+            body.angular_velocity =
+                body.angular_velocity * (1.0 / (1.0 + config.angular_damping * dt));
+        }
+
+        for _ in 0..SOLVER_ITERATIONS {
+            self.resolve_collisions(&mut solids);
+            for solid in solids.iter_mut().filter(|solid| solid.is_moving()) {
+                if let Some(ground) = ground_below(&grounds, solid.position.y) {
+                    self.resolve_ground(solid, ground);
+                }
+            }
+        }
+
+        for solid in solids.iter_mut().filter(|solid| solid.is_moving()) {
+            solid.position += solid.body.velocity * dt;
+            solid.body.orientation = solid
+                .body
+                .orientation
+                .integrate(solid.body.angular_velocity, dt);
+            if let Some(ground) = ground_below(&grounds, solid.position.y) {
+                let lowest = solid
+                    .shape
+                    .contact_points(solid.body.orientation)
+                    .iter()
+                    .map(|offset| solid.position.y + offset.y)
+                    .fold(f32::INFINITY, f32::min);
+                if lowest < ground {
+                    solid.position.y += ground - lowest;
+                }
+            }
+            if let Some(bounds) = &self.world_bounds {
+                // The sides of the world stop items like walls
+                let inside = Vec3::from(bounds.clamp(solid.position.into()));
+                let velocity = &mut solid.body.velocity;
+                for (clamped, position, velocity) in [
+                    (inside.x, solid.position.x, &mut velocity.x),
+                    (inside.y, solid.position.y, &mut velocity.y),
+                    (inside.z, solid.position.z, &mut velocity.z),
+                ] {
+                    if clamped != position {
+                        *velocity = 0.0;
+                    }
+                }
+                solid.position = inside;
+            }
+
+            let body = &mut solid.body;
+            match body.velocity.length() < SLEEP_SPEED
+                && body.angular_velocity.length() < SLEEP_SPEED
+            {
+                true => body.resting_for += dt,
+                false => body.resting_for = 0.0,
+            }
+            if body.resting_for > SLEEP_AFTER {
+                body.asleep = true;
+                body.velocity = Vec3::ZERO;
+                body.angular_velocity = Vec3::ZERO;
+            }
+        }
+
+        for solid in solids.into_iter().filter(|solid| solid.is_dynamic()) {
+            let item = &mut items[solid.index];
+            let mut body = solid.body;
+            if !body.asleep {
+                item.position = solid.position.into();
+                item.rotation = body.orientation.to_euler_xyz();
+            }
+            body.rotation = item.rotation;
+            self.bodies.insert(item.id.clone(), body);
+        }
+    }
+
+    /// Pushes overlapping items apart, treating each as a sphere around it
+    fn resolve_collisions(&self, solids: &mut [Solid]) {
+        for i in 0..solids.len() {
+            let (head, tail) = solids.split_at_mut(i + 1);
+            let a = &mut head[i];
+            for b in tail.iter_mut() {
+                if !a.is_moving() && !b.is_moving() {
+                    continue;
+                }
+                let between = b.position - a.position;
+                let distance = between.length();
+                let reach = a.shape.bounding_radius() + b.shape.bounding_radius();
+                let inverse_mass = a.inverse_mass + b.inverse_mass;
+                if distance >= reach || inverse_mass == 0.0 {
+                    continue;
+                }
+
+                // Items spawned on top of each other have no direction between them, stack them
+                let normal = match distance < f32::EPSILON {
+                    true => Vec3::UP,
+                    false => between * (1.0 / distance),
+                };
+                let closing_speed = (b.body.velocity - a.body.velocity).dot(normal);
+                if closing_speed < 0.0 {
+                    let restitution = match -closing_speed > BOUNCE_SPEED {
+                        true => self.config.restitution,
+                        false => 0.0,
+                    };
+                    let impulse = normal * (-(1.0 + restitution) * closing_speed / inverse_mass);
+                    a.body.velocity -= impulse * a.inverse_mass;
+                    b.body.velocity += impulse * b.inverse_mass;
+                }
+                let correction = normal * ((reach - distance) / inverse_mass / 2.0);
+                a.position -= correction * a.inverse_mass;
+                b.position += correction * b.inverse_mass;
+                for solid in [&mut *a, b] {
+                    if solid.is_dynamic() {
+                        solid.body.wake();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stops the points of `solid` touching the ground from moving into it, with friction
+    fn resolve_ground(&self, solid: &mut Solid, ground: f32) {
+        for offset in solid.shape.contact_points(solid.body.orientation) {
+            if solid.position.y + offset.y - ground > CONTACT_SLOP {
+                continue;
+            }
+
+            let normal_speed = solid.point_velocity(offset).dot(Vec3::UP);
+            if normal_speed >= 0.0 {
+                continue;
+            }
+            let restitution = match -normal_speed > BOUNCE_SPEED {
+                true => self.config.restitution,
+                false => 0.0,
+            };
+            let normal_impulse =
+                -(1.0 + restitution) * normal_speed / solid.inverse_mass_along(offset, Vec3::UP);
+            solid.apply_impulse(Vec3::UP * normal_impulse, offset);
+
+            let velocity = solid.point_velocity(offset);
+            let sliding = velocity - Vec3::UP * velocity.dot(Vec3::UP);
+            let sliding_speed = sliding.length();
+            if sliding_speed < f32::EPSILON {
+                continue;
+            }
+            let tangent = sliding * (1.0 / sliding_speed);
+            let friction_impulse = (sliding_speed / solid.inverse_mass_along(offset, tangent))
+                .min(self.config.friction * normal_impulse);
+            solid.apply_impulse(tangent * -friction_impulse, offset);
+        }
+    }
+}
+
+/// The highest ground at or below `y`, items under every plane keep falling
+fn ground_below(grounds: &[f32], y: f32) -> Option<f32> {
+    grounds
+        .iter()
+        .copied()
+        .filter(|ground| *ground <= y)
+        .fold(None, |highest, ground| {
+            Some(highest.map_or(ground, |highest: f32| highest.max(ground)))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::color::Color;
+
+    use super::*;
+
+    fn sphere(id: &str, position: (f32, f32, f32)) -> SceneItem {
+        SceneItem {
+            mesh_type: MeshType::Sphere,
+            id: id.into(),
+            position,
+            rotation: (0.0, 0.0, 0.0),
+            color: Color::from_hex("#FF0000").unwrap(),
+            scale: (1.0, 1.0, 1.0),
+        }
+    }
+
+    fn settle(world: &mut PhysicsWorld, items: &mut [SceneItem]) {
+        let simulated = vec![true; items.len()];
+        for _ in 0..600 {
+            world.step(items, &simulated, vec![], 1.0 / 60.0);
+        }
+    }
+
+    #[test]
+    fn rests_on_default_ground_without_plane() {
+        let mut world = PhysicsWorld::new(
+            PhysicsConfig {
+                ground_y: 2.0,
+                ..PhysicsConfig::default()
+            },
+            None,
+        );
+        let mut items = [sphere("a", (0.0, 5.0, 0.0))];
+        settle(&mut world, &mut items);
+        assert!((items[0].position.1 - 2.5).abs() < 0.05, "{:?}", items[0]);
+    }
+
+    fn cube(id: &str, position: (f32, f32, f32)) -> SceneItem {
+        SceneItem {
+            mesh_type: MeshType::Cube,
+            ..sphere(id, position)
+        }
+    }
+
+    #[test]
+    fn rests_on_plane() {
+        let mut world = PhysicsWorld::new(PhysicsConfig::default(), None);
+        let mut items = [
+            SceneItem {
+                mesh_type: MeshType::Plane,
+                ..sphere("ground", (0.0, 1.0, 0.0))
+            },
+            cube("a", (0.0, 4.0, 0.0)),
+        ];
+        settle(&mut world, &mut items);
+        let rested = items[1].position;
+        assert!((rested.1 - 1.5).abs() < 0.05, "{:?}", items[1]);
+
+        settle(&mut world, &mut items);
+        assert_eq!(items[1].position, rested);
+        assert_eq!(items[0].position, (0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn separates_items_with_coincident_centers() {
+        let mut world = PhysicsWorld::new(PhysicsConfig::default(), None);
+        let mut items = [sphere("a", (0.0, 3.0, 0.0)), sphere("b", (0.0, 3.0, 0.0))];
+        world.step(&mut items, &[true, true], vec![], 1.0 / 60.0);
+        assert!(items[1].position.1 > items[0].position.1, "{:?}", items);
+
+        settle(&mut world, &mut items);
+        let [a, b] = &items;
+        let between = Vec3::from(b.position) - Vec3::from(a.position);
+        assert!(between.length() > 0.9, "{:?}", items);
+        assert!(
+            items.iter().all(|item| item.position.1 > -0.05),
+            "{:?}",
+            items
+        );
+    }
+
+    #[test]
+    fn stays_inside_world_bounds() {
+        let bounds = WorldBounds {
+            min: (-1.0, -1.0, -1.0),
+            max: (1.0, 10.0, 1.0),
+        };
+        let mut world = PhysicsWorld::new(PhysicsConfig::default(), Some(bounds));
+        let mut items = [sphere("a", (0.0, 0.5, 0.0))];
+        let impulse = Impulse {
+            linear: (1000.0, 0.0, 0.0),
+            angular: (0.0, 0.0, 0.0),
+        };
+        world.step(&mut items, &[true], vec![("a".into(), impulse)], 1.0 / 60.0);
+        settle(&mut world, &mut items);
+        assert_eq!(items[0].position.0, 1.0);
+    }
+}
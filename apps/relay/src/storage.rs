@@ -0,0 +1,81 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use tracing::{error, info};
+
+use crate::{scene::Scene, util};
+
+/// Stores org scenes as `<dir>/<org id>.json` so they survive a relay restart
+#[derive(Debug, Clone)]
+pub struct SceneStorage {
+    dir: PathBuf,
+}
+
+impl SceneStorage {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path_for(&self, org_id: &str) -> PathBuf {
+        self.dir.join(util::org_file_name(org_id, "json"))
+    }
+
+    /// Loads a previously saved scene
+    pub async fn load(&self, org_id: &str) -> Option<Scene> {
+        let path = self.path_for(org_id);
+        let contents = tokio::fs::read(&path).await.ok()?;
+        match serde_json::from_slice(&contents) {
+            Ok(scene) => {
+                info!(org_id, ?path, "Loaded scene from storage");
+                Some(scene)
+            }
+            Err(err) => {
+                error!(org_id, ?path, error = ?err, "Stored scene is invalid, ignoring it");
+                None
+            }
+        }
+    }
+
+    pub async fn save(&self, org_id: &str, scene: &Scene) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .context("Failed to create storage directory")?;
+        let contents = serde_json::to_vec(scene).context("Failed to serialize scene")?;
+        tokio::fs::write(self.path_for(org_id), contents)
+            .await
+            .context("Failed to write scene")
+    }
+
+    /// Writes and removes a probe file to check the directory is still writable
+    pub async fn check(&self) -> anyhow::Result<()> {
+        let probe = self.dir.join(".readyz");
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .context("Failed to create storage directory")?;
+        tokio::fs::write(&probe, b"")
+            .await
+            .context("Failed to write probe file")?;
+        tokio::fs::remove_file(&probe)
+            .await
+            .context("Failed to remove probe file")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scene::create_test_scene;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn saved_scenes_load_again() {
+        let dir = std::env::temp_dir().join(format!("relay-storage-{}", std::process::id()));
+        let storage = SceneStorage::new(dir.clone());
+        storage.save("org", &create_test_scene()).await.unwrap();
+
+        let scene = storage.load("org").await.expect("scene was saved");
+        assert_eq!(scene.items.len(), create_test_scene().items.len());
+        assert!(storage.load("other").await.is_none());
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
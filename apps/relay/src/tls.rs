@@ -0,0 +1,90 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use serde::Deserialize;
+use tokio::time::sleep;
+use tracing::{error, info, instrument};
+
+use crate::config::millis;
+
+const DEFAULT_RELOAD_INTERVAL_MS: u64 = 60_000;
+
+/// Serves `wss://` directly when both files are set, for deployments without a proxy in front
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first
+    pub cert_file: Option<PathBuf>,
+    /// PEM private key for the leaf certificate
+    pub key_file: Option<PathBuf>,
+    /// How often the files are checked for changes, e.g. after a certificate renewal
+    #[serde(rename = "reload_interval_ms", deserialize_with = "millis")]
+    pub reload_interval: Duration,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_file: None,
+            key_file: None,
+            reload_interval: Duration::from_millis(DEFAULT_RELOAD_INTERVAL_MS),
+        }
+    }
+}
+
+fn modified(path: &Path) -> anyhow::Result<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .with_context(|| format!("Failed to read {:?}", path))
+}
+
+pub async fn load(cert_file: &Path, key_file: &Path) -> anyhow::Result<RustlsConfig> {
+    // Fails when a provider is already installed, which is fine
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let config = RustlsConfig::from_pem_file(cert_file, key_file)
+        .await
+        .with_context(|| format!("Failed to load certificate {:?}", cert_file))?;
+    info!(?cert_file, "Loaded TLS certificate");
+    Ok(config)
+}
+
+/// Polls the certificate and key and swaps them in when either changes, new handshakes use the
+/// new certificate while established connections keep theirs
+#[instrument(skip(config))]
+pub async fn reload_certificate_task(
+    config: RustlsConfig,
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    interval: Duration,
+) {
+    let mut loaded = (modified(&cert_file).ok(), modified(&key_file).ok());
+    loop {
+        sleep(interval).await;
+        let current = match (modified(&cert_file), modified(&key_file)) {
+            (Ok(cert_modified), Ok(key_modified)) => (Some(cert_modified), Some(key_modified)),
+            (Err(err), _) | (_, Err(err)) => {
+                error!(error = ?err, "Failed to check TLS certificate, keeping the previous one");
+                continue;
+            }
+        };
+        if current == loaded {
+            continue;
+        }
+
+        // A renewal may replace the files one at a time, a mismatched pair fails here and is
+        // retried on the next tick because `loaded` is left as it was
+        match config.reload_from_pem_file(&cert_file, &key_file).await {
+            Ok(()) => {
+                info!(?cert_file, "Reloaded TLS certificate");
+                loaded = current;
+            }
+            Err(err) => {
+                error!(error = ?err, "Failed to reload TLS certificate, keeping the previous one")
+            }
+        }
+    }
+}
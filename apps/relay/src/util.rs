@@ -1,21 +1,61 @@
-use axum::extract::ws::Message;
+use std::net::{IpAddr, SocketAddr};
 
-pub struct ErrorFormatter {}
+use axum::http::HeaderMap;
 
-impl ErrorFormatter {
-    pub fn format_axum_error(err: axum::Error) -> String {
-        format!("{:?}", err)
-    }
+/// The ip a request came from, taken from the left most `x-forwarded-for` entry when the relay
+/// runs behind a proxy that sets it
+pub fn client_ip(headers: &HeaderMap, addr: SocketAddr, trust_forwarded_for: bool) -> IpAddr {
+    trust_forwarded_for
+        .then(|| {
+            headers
+                .get("x-forwarded-for")?
+                .to_str()
+                .ok()?
+                .split(',')
+                .next()?
+                .trim()
+                .parse()
+                .ok()
+        })
+        .flatten()
+        .unwrap_or(addr.ip())
+}
+
+/// Org ids come from the url, every UTF-8 byte outside `[A-Za-z0-9-]` is escaped as `_xx` so a
+/// file named after an org can never point outside its directory and no two ids share a file
+pub fn org_file_name(org_id: &str, extension: &str) -> String {
+    let name = org_id
+        .bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' => (byte as char).to_string(),
+            _ => format!("_{:02x}", byte),
+        })
+        .collect::<String>();
+    format!("{}.{}", name, extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub fn format_serde_error(err: serde_json::Error) -> String {
-        format!("{:?}", err)
+    #[test]
+    fn org_file_name_keeps_safe_characters() {
+        assert_eq!(org_file_name("acme-Prod-01", "json"), "acme-Prod-01.json");
     }
 
-    pub fn format_join_error(err: tokio::task::JoinError) -> String {
-        format!("{:?}", err)
+    #[test]
+    fn org_file_name_escapes_path_characters() {
+        assert_eq!(org_file_name("../etc", "json"), "_2e_2e_2fetc.json");
+        assert_eq!(org_file_name("a_b", "rhai"), "a_5fb.rhai");
     }
 
-    pub fn format_ws_send_error(err: tokio::sync::mpsc::error::SendError<Message>) -> String {
-        format!("{:?}", err)
+    #[test]
+    fn org_file_name_escapes_every_utf8_byte() {
+        assert_eq!(org_file_name("é", "json"), "_c3_a9.json");
+        // Escaping code points instead would map both of these to `_101`
+        assert_ne!(
+            org_file_name("\u{101}", "json"),
+            org_file_name("\u{10}1", "json")
+        );
     }
 }
@@ -0,0 +1,288 @@
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::scene::{Scene, SceneUpdate};
+
+const DEFAULT_MAX_ID_LENGTH: usize = 128;
+
+/// Checks game server updates after parsing and before they are queued for broadcast
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValidationConfig {
+    /// Positions outside the box are rejected, any finite position is accepted when unset
+    pub world_bounds: Option<WorldBounds>,
+    pub unknown_items: UnknownItemPolicy,
+    pub max_id_length: usize,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            world_bounds: None,
+            unknown_items: UnknownItemPolicy::default(),
+            max_id_length: DEFAULT_MAX_ID_LENGTH,
+        }
+    }
+}
+
+/// Axis aligned box every item position has to stay in, both corners included
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WorldBounds {
+    pub min: (f32, f32, f32),
+    pub max: (f32, f32, f32),
+}
+
+impl WorldBounds {
+    fn contains(&self, (x, y, z): (f32, f32, f32)) -> bool {
+        (self.min.0..=self.max.0).contains(&x)
+            && (self.min.1..=self.max.1).contains(&y)
+            && (self.min.2..=self.max.2).contains(&z)
+    }
+
+    /// The closest position inside the box
+    pub fn clamp(&self, (x, y, z): (f32, f32, f32)) -> (f32, f32, f32) {
+        (
+            x.clamp(self.min.0, self.max.0),
+            y.clamp(self.min.1, self.max.1),
+            z.clamp(self.min.2, self.max.2),
+        )
+    }
+
+    pub fn is_valid(&self) -> bool {
+        let (min, max) = (self.min, self.max);
+        [min.0, min.1, min.2, max.0, max.1, max.2]
+            .iter()
+            .all(|value| value.is_finite())
+            && min.0 <= max.0
+            && min.1 <= max.1
+            && min.2 <= max.2
+    }
+}
+
+/// `min_x,min_y,min_z,max_x,max_y,max_z`, the format of `WORLD_BOUNDS`
+impl FromStr for WorldBounds {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let values = value
+            .split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|err| err.to_string())?;
+        match values[..] {
+            [min_x, min_y, min_z, max_x, max_y, max_z] => Ok(WorldBounds {
+                min: (min_x, min_y, min_z),
+                max: (max_x, max_y, max_z),
+            }),
+            _ => Err("expected min_x,min_y,min_z,max_x,max_y,max_z".into()),
+        }
+    }
+}
+
+/// What happens to an update for an item that is not in the org scene
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownItemPolicy {
+    /// The update is rejected, items only come from the scene
+    #[default]
+    Reject,
+    /// The item is added to the scene with default fields for the ones the update leaves out
+    Spawn,
+}
+
+impl FromStr for UnknownItemPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "reject" => Ok(UnknownItemPolicy::Reject),
+            "spawn" => Ok(UnknownItemPolicy::Spawn),
+            _ => Err("expected reject or spawn".into()),
+        }
+    }
+}
+
+/// Why an update was rejected, sent back to the game server that sent it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InvalidUpdate {
+    EmptyId,
+    IdTooLong,
+    NonFinitePosition,
+    NonFiniteRotation,
+    OutOfBounds,
+    UnknownItem,
+    NonFiniteImpulse,
+}
+
+impl InvalidUpdate {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InvalidUpdate::EmptyId => "empty_id",
+            InvalidUpdate::IdTooLong => "id_too_long",
+            InvalidUpdate::NonFinitePosition => "non_finite_position",
+            InvalidUpdate::NonFiniteRotation => "non_finite_rotation",
+            InvalidUpdate::OutOfBounds => "out_of_bounds",
+            InvalidUpdate::UnknownItem => "unknown_item",
+            InvalidUpdate::NonFiniteImpulse => "non_finite_impulse",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            InvalidUpdate::EmptyId => "id must not be empty",
+            InvalidUpdate::IdTooLong => "id is longer than the relay allows",
+            InvalidUpdate::NonFinitePosition => "position must not contain NaN or infinity",
+            InvalidUpdate::NonFiniteRotation => "rotation must not contain NaN or infinity",
+            InvalidUpdate::OutOfBounds => "position is outside the world bounds",
+            InvalidUpdate::UnknownItem => "item is not in the scene",
+            InvalidUpdate::NonFiniteImpulse => "impulse must not contain NaN or infinity",
+        }
+    }
+}
+
+fn is_finite((x, y, z): (f32, f32, f32)) -> bool {
+    x.is_finite() && y.is_finite() && z.is_finite()
+}
+
+impl ValidationConfig {
+    pub fn validate(&self, update: &SceneUpdate, scene: &Scene) -> Result<(), InvalidUpdate> {
+        if update.id.is_empty() {
+            return Err(InvalidUpdate::EmptyId);
+        }
+        if update.id.len() > self.max_id_length {
+            return Err(InvalidUpdate::IdTooLong);
+        }
+        if let Some(position) = update.position {
+            if !is_finite(position) {
+                return Err(InvalidUpdate::NonFinitePosition);
+            }
+            if self
+                .world_bounds
+                .is_some_and(|bounds| !bounds.contains(position))
+            {
+                return Err(InvalidUpdate::OutOfBounds);
+            }
+        }
+        if update.rotation.is_some_and(|rotation| !is_finite(rotation)) {
+            return Err(InvalidUpdate::NonFiniteRotation);
+        }
+
+        let is_known = scene.items.iter().any(|item| item.id == update.id);
+        if !is_known && self.unknown_items == UnknownItemPolicy::Reject {
+            return Err(InvalidUpdate::UnknownItem);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scene::{create_test_scene, test_update};
+
+    use super::*;
+
+    fn bounded() -> ValidationConfig {
+        ValidationConfig {
+            world_bounds: Some("-10,0,-10,10,20,10".parse().unwrap()),
+            ..ValidationConfig::default()
+        }
+    }
+
+    #[test]
+    fn accepts_known_items() {
+        let update = SceneUpdate {
+            position: Some((1.0, 2.0, 3.0)),
+            rotation: Some((0.0, 1.5, 0.0)),
+            ..test_update("0")
+        };
+        assert_eq!(bounded().validate(&update, &create_test_scene()), Ok(()));
+    }
+
+    #[test]
+    fn rejects_non_finite_values() {
+        let scene = create_test_scene();
+        let config = ValidationConfig::default();
+        for position in [(f32::NAN, 0.0, 0.0), (0.0, f32::INFINITY, 0.0)] {
+            let update = SceneUpdate {
+                position: Some(position),
+                ..test_update("0")
+            };
+            assert_eq!(
+                config.validate(&update, &scene),
+                Err(InvalidUpdate::NonFinitePosition)
+            );
+        }
+        let update = SceneUpdate {
+            rotation: Some((0.0, 0.0, f32::NEG_INFINITY)),
+            ..test_update("0")
+        };
+        assert_eq!(
+            config.validate(&update, &scene),
+            Err(InvalidUpdate::NonFiniteRotation)
+        );
+    }
+
+    #[test]
+    fn rejects_positions_out_of_bounds() {
+        let scene = create_test_scene();
+        let at = |position| SceneUpdate {
+            position: Some(position),
+            ..test_update("0")
+        };
+        assert_eq!(bounded().validate(&at((10.0, 0.0, -10.0)), &scene), Ok(()));
+        assert_eq!(
+            bounded().validate(&at((10.1, 0.0, 0.0)), &scene),
+            Err(InvalidUpdate::OutOfBounds)
+        );
+        assert_eq!(
+            bounded().validate(&at((0.0, -0.1, 0.0)), &scene),
+            Err(InvalidUpdate::OutOfBounds)
+        );
+        assert_eq!(
+            ValidationConfig::default().validate(&at((1e30, 0.0, 0.0)), &scene),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn checks_ids() {
+        let scene = create_test_scene();
+        let config = ValidationConfig {
+            max_id_length: 3,
+            ..ValidationConfig::default()
+        };
+        assert_eq!(
+            config.validate(&test_update(""), &scene),
+            Err(InvalidUpdate::EmptyId)
+        );
+        assert_eq!(
+            config.validate(&test_update("long"), &scene),
+            Err(InvalidUpdate::IdTooLong)
+        );
+    }
+
+    #[test]
+    fn unknown_items_follow_policy() {
+        let scene = create_test_scene();
+        let mut config = ValidationConfig::default();
+        assert_eq!(
+            config.validate(&test_update("new"), &scene),
+            Err(InvalidUpdate::UnknownItem)
+        );
+        config.unknown_items = UnknownItemPolicy::Spawn;
+        assert_eq!(config.validate(&test_update("new"), &scene), Ok(()));
+    }
+
+    #[test]
+    fn parses_world_bounds() {
+        let bounds: WorldBounds = " -1, -2, -3, 1, 2, 3 ".parse().unwrap();
+        assert_eq!(bounds.min, (-1.0, -2.0, -3.0));
+        assert_eq!(bounds.max, (1.0, 2.0, 3.0));
+        assert!(bounds.is_valid());
+        assert!("1,2,3".parse::<WorldBounds>().is_err());
+        assert!(!"1,0,0,-1,0,0".parse::<WorldBounds>().unwrap().is_valid());
+        assert!(!"nan,0,0,1,1,1".parse::<WorldBounds>().unwrap().is_valid());
+    }
+}
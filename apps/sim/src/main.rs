@@ -2,7 +2,7 @@ use std::fs::read;
 use std::path;
 use std::str::FromStr;
 
-use clap::{arg, command, Parser};
+use clap::Parser;
 
 use futures_util::SinkExt;
 use futures_util::StreamExt;
@@ -60,20 +60,14 @@ async fn main() {
 
     let frames = read(path::Path::new(&args.file)).expect("Failed to read file");
 
+    // The relay takes a whole tick as one array, so viewers get it in a single batch
     let scene_updates = serde_json::from_slice::<Vec<Vec<SceneUpdate>>>(&frames)
         .expect("Failed to parse json")
         .iter()
         .map(|frame| {
-            frame
-                .iter()
-                .map(|update| {
-                    tungstenite::Message::Text(
-                        serde_json::to_string(update).expect("Failed to serialize"),
-                    )
-                })
-                .collect()
+            tungstenite::Message::Text(serde_json::to_string(frame).expect("Failed to serialize"))
         })
-        .collect::<Vec<Vec<Message>>>();
+        .collect::<Vec<Message>>();
 
     let server_uri = uri::Uri::from_str(&format!("{}/game/{}", &args.server, &args.game_id))
         .expect("Failed to parse uri");
@@ -102,14 +96,12 @@ async fn main() {
 
     info!("Running sim ");
     loop {
-        for update in scene_updates.iter() {
+        for frame in scene_updates.iter() {
             tokio::time::sleep(std::time::Duration::from_millis(25)).await;
-            for frame in update.iter() {
-                write
-                    .send(frame.clone())
-                    .await
-                    .expect("Failed to send message");
-            }
+            write
+                .send(frame.clone())
+                .await
+                .expect("Failed to send message");
         }
     }
 }
@@ -1,7 +1,12 @@
 "use client";
-import { SceneItem } from "@/server/api/routers/scene";
+import { env } from "@/env";
+import type {
+    Scene as SceneData,
+    SceneItem,
+} from "@/server/api/routers/scene";
 import { api } from "@/trpc/react";
 import { Canvas, useFrame, useThree } from "@react-three/fiber";
+import { useQuery } from "@tanstack/react-query";
 import {
     Bloom,
     ChromaticAberration,
@@ -10,14 +15,83 @@ import {
     Noise,
 } from "@react-three/postprocessing";
 import { useEffect, useRef, useState } from "react";
-import { damp } from "three/src/math/MathUtils.js";
+import { lerp } from "three/src/math/MathUtils.js";
 
 import { OrbitControls, RoundedBox } from "@react-three/drei";
 import { BlendFunction } from "postprocessing";
-import { PerspectiveCamera } from "three";
+import { Color, Euler, PerspectiveCamera, Quaternion } from "three";
 
-const useSmoothing = false;
+const useSmoothing = true;
 const makePretty = true;
+// Items are drawn this far behind the relay so there is usually a tick on either side to
+// interpolate between
+const interpolationDelayMs = 100;
+const maxSnapshots = 10;
+
+type Vector = [number, number, number];
+
+type SceneUpdate = {
+    id: string;
+    color?: string;
+    position?: Vector;
+    rotation?: Vector;
+    // Only set when the update spawns the item
+    meshType?: SceneItem["meshType"];
+    scale?: Vector;
+    // Set when the item left the scene, nothing else is
+    despawned?: boolean;
+};
+
+type Tick = {
+    type: "tick";
+    tick: number;
+    serverTimeMs: number;
+    updates: SceneUpdate[];
+};
+
+type Snapshot = {
+    timeMs: number;
+    position: Vector;
+    rotation: Quaternion;
+    color: Color;
+};
+
+function snapshotOf(item: SceneItem, timeMs: number): Snapshot {
+    return {
+        timeMs,
+        position: item.position,
+        rotation: new Quaternion().setFromEuler(new Euler(...item.rotation)),
+        color: new Color(item.color),
+    };
+}
+
+// Item state at `timeMs` between the snapshots around it, held at the ends.
+// Rotations are slerped so items turn the short way round
+function interpolate(
+    snapshots: Snapshot[],
+    timeMs: number,
+): Snapshot | undefined {
+    const next = snapshots.findIndex((snapshot) => snapshot.timeMs >= timeMs);
+    if (next === -1) {
+        return snapshots[snapshots.length - 1];
+    }
+    const after = snapshots[next]!;
+    const before = snapshots[next - 1];
+    if (!before) {
+        return after;
+    }
+    const t = (timeMs - before.timeMs) / (after.timeMs - before.timeMs);
+    return {
+        timeMs,
+        position: [
+            lerp(before.position[0], after.position[0], t),
+            lerp(before.position[1], after.position[1], t),
+            lerp(before.position[2], after.position[2], t),
+        ],
+        rotation: before.rotation.clone().slerp(after.rotation, t),
+        color: before.color.clone().lerp(after.color, t),
+    };
+}
 
 export default function Home() {
     return (
@@ -39,19 +113,20 @@ export default function Home() {
 }
 
 function useWebsocket(opts: {
-    onMessage: (
-        data: {
-            id: string;
-            color?: string;
-            position?: [number, number, number];
-            rotation?: [number, number, number];
-        }[],
-    ) => void;
+    onMessage: (tick: Tick) => void;
     orgName: string;
+    token?: string | null;
+    // Connecting before the token is known would always fail the relay's auth check
+    enabled: boolean;
 }) {
     useEffect(() => {
+        if (!opts.enabled) {
+            return;
+        }
+        // Browsers cannot set headers on websockets, the relay reads the token from the subprotocol
         const ws = new WebSocket(
-            `ws://localhost:3002/sub/${encodeURIComponent(opts.orgName)}`,
+            `${env.NEXT_PUBLIC_RELAY_URL}/sub/${encodeURIComponent(opts.orgName)}`,
+            opts.token ? ["viewer-token", opts.token] : undefined,
         );
         ws.onopen = () => {
             console.log(
@@ -61,6 +136,10 @@ function useWebsocket(opts: {
         };
         ws.onmessage = (event) => {
             const payload = JSON.parse(event.data);
+            // Control messages such as `restarting` have other types
+            if (payload.type !== "tick") {
+                return;
+            }
             // console.log(
             //     "%cMessage",
             //     "background:#A001fF;padding:0.5rem",
@@ -77,7 +156,21 @@ function useWebsocket(opts: {
         return () => {
             ws.close();
         };
-    }, [opts.orgName]);
+    }, [opts.orgName, opts.token, opts.enabled]);
+}
+
+// The relay answers with CORS headers for allowed origins, so the scene is fetched directly.
+// Orgs the relay has never seen are a 404, the query retries once the socket joined it
+function fetchScene(orgName: string) {
+    const relayUrl = env.NEXT_PUBLIC_RELAY_URL.replace(/^ws/, "http");
+    return fetch(`${relayUrl}/scene/${encodeURIComponent(orgName)}`).then(
+        (res): Promise<SceneData> => {
+            if (!res.ok) {
+                throw new Error(`Failed to fetch scene: ${res.status}`);
+            }
+            return res.json();
+        },
+    );
 }
 
 function Scene() {
@@ -89,14 +182,17 @@ function Scene() {
         () => cameraRef.current && void set({ camera: cameraRef.current }),
         [],
     );
-    const scene = api.scene.getSceneByOrg.useQuery(
-        { orgName: "test" },
-        {
-            refetchOnWindowFocus: false,
-            refetchInterval: 0,
-        },
-    );
+    const scene = useQuery({
+        queryKey: ["scene", "finn"],
+        queryFn: () => fetchScene("finn"),
+        refetchOnWindowFocus: false,
+        refetchInterval: 0,
+    });
     const sceneRef = useRef<SceneItem[] | undefined>(scene.data?.items);
+    // Recent positions of every item by server time, and how far the relay clock is ahead
+    const snapshotsRef = useRef(new Map<string, Snapshot[]>());
+    const clockOffsetRef = useRef<number>();
+    const lastTickTimeRef = useRef<number>();
 
     const [, reRender] = useState<any | null>({});
     useEffect(() => {
@@ -104,24 +200,88 @@ function Scene() {
         reRender({});
     }, [scene.data?.items]);
     console.log(theScene);
+    const viewerToken = api.org.viewerToken.useQuery(
+        { orgName: "finn" },
+        { refetchOnWindowFocus: false, retry: false },
+    );
     useWebsocket({
         orgName: "finn",
-        onMessage(data) {
+        token: viewerToken.data,
+        enabled: viewerToken.isFetched,
+        onMessage(tick) {
+            // The fastest tick to arrive gives the closest estimate of the relay clock
+            const offset = tick.serverTimeMs - Date.now();
+            clockOffsetRef.current = Math.max(
+                clockOffsetRef.current ?? offset,
+                offset,
+            );
+            const previousTickTime =
+                lastTickTimeRef.current ?? tick.serverTimeMs;
+            lastTickTimeRef.current = tick.serverTimeMs;
             if (sceneRef.current) {
-                for (const message of data) {
+                // Spawns and despawns change which meshes are drawn
+                let itemsChanged = false;
+                for (const message of tick.updates) {
+                    if (message.despawned) {
+                        sceneRef.current = sceneRef.current.filter(
+                            (item) => item.id !== message.id,
+                        );
+                        snapshotsRef.current.delete(message.id);
+                        itemsChanged = true;
+                        continue;
+                    }
+
                     const itemToUpdate = sceneRef.current?.find(
                         (item) => item.id === message.id,
                     );
 
                     if (!itemToUpdate) {
+                        // Items spawned after the scene was fetched come with their mesh
+                        if (message.meshType) {
+                            sceneRef.current.push({
+                                id: message.id,
+                                meshType: message.meshType,
+                                position: message.position ?? [0, 0, 0],
+                                rotation: message.rotation ?? [0, 0, 0],
+                                color: message.color ?? "#FFFFFF",
+                                scale: message.scale,
+                            });
+                            itemsChanged = true;
+                        }
+                        continue;
+                    }
+
+                    if (
+                        !message.position &&
+                        !message.rotation &&
+                        !message.color
+                    ) {
                         continue;
                     }
+                    const snapshots =
+                        snapshotsRef.current.get(message.id) ?? [];
+                    const last = snapshots[snapshots.length - 1];
+                    // An item that rested moves from where it was at the previous tick
+                    if (!last || last.timeMs < previousTickTime) {
+                        snapshots.push(
+                            snapshotOf(itemToUpdate, previousTickTime),
+                        );
+                    }
 
                     itemToUpdate.color = message.color ?? itemToUpdate.color;
                     itemToUpdate.position =
                         message.position ?? itemToUpdate.position;
                     itemToUpdate.rotation =
                         message.rotation ?? itemToUpdate.rotation;
+
+                    snapshots.push(snapshotOf(itemToUpdate, tick.serverTimeMs));
+                    snapshotsRef.current.set(
+                        message.id,
+                        snapshots.slice(-maxSnapshots),
+                    );
+                }
+                if (itemsChanged) {
+                    reRender({});
                 }
             }
         },
@@ -136,30 +296,22 @@ function Scene() {
                 if (!current) {
                     continue;
                 }
-                const targetPos = target.position;
-                if (!useSmoothing) {
-                    current.position.set(
-                        targetPos[0] ?? 0,
-                        targetPos[1] ?? 0,
-                        targetPos[2] ?? 0,
-                    );
-                } else {
-                    current.position.set(
-                        damp(current.position.x, targetPos[0] ?? 0, 1.45, 0.01),
-                        damp(current.position.y, targetPos[1] ?? 0, 1.45, 0.01),
-                        damp(current.position.z, targetPos[2] ?? 0, 1.45, 0.01),
-                    );
-                }
-                const targetRot = target.rotation;
-                current.rotation.set(
-                    damp(current.rotation.x, targetRot[0] ?? 0, 0.45, 0.01),
-                    damp(current.rotation.y, targetRot[1] ?? 0, 0.45, 0.01),
-                    damp(current.rotation.z, targetRot[2] ?? 0, 0.45, 0.01),
-                );
-                current.rotation.set(0, targetRot[0], 0);
+                const renderTime =
+                    Date.now() +
+                    (clockOffsetRef.current ?? 0) -
+                    interpolationDelayMs;
+                const state =
+                    (useSmoothing &&
+                        interpolate(
+                            snapshotsRef.current.get(target.id) ?? [],
+                            renderTime,
+                        )) ||
+                    snapshotOf(target, renderTime);
+                current.position.set(...state.position);
+                current.quaternion.copy(state.rotation);
 
-                (current as any).material.color.set(target.color);
-                (current as any).material.emissive.set(target.color);
+                (current as any).material.color.copy(state.color);
+                (current as any).material.emissive.copy(state.color);
             }
         }
     });
@@ -24,6 +24,7 @@ export const env = createEnv({
         ),
         DISCORD_CLIENT_ID: z.string(),
         DISCORD_CLIENT_SECRET: z.string(),
+        RELAY_VIEWER_TOKEN_SECRET: z.string().optional(),
     },
 
     /**
@@ -32,7 +33,8 @@ export const env = createEnv({
      * `NEXT_PUBLIC_`.
      */
     client: {
-        // NEXT_PUBLIC_CLIENTVAR: z.string(),
+        // wss:// when the relay terminates TLS itself
+        NEXT_PUBLIC_RELAY_URL: z.string().url().default("ws://localhost:3002"),
     },
 
     /**
@@ -46,6 +48,8 @@ export const env = createEnv({
         NEXTAUTH_URL: process.env.NEXTAUTH_URL,
         DISCORD_CLIENT_ID: process.env.DISCORD_CLIENT_ID,
         DISCORD_CLIENT_SECRET: process.env.DISCORD_CLIENT_SECRET,
+        RELAY_VIEWER_TOKEN_SECRET: process.env.RELAY_VIEWER_TOKEN_SECRET,
+        NEXT_PUBLIC_RELAY_URL: process.env.NEXT_PUBLIC_RELAY_URL,
     },
     /**
      * Run `build` or `dev` with `SKIP_ENV_VALIDATION` to skip env validation. This is especially
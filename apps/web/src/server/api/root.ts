@@ -1,4 +1,3 @@
-import { sceneRouter } from "@/server/api/routers/scene";
 import { createCallerFactory, createTRPCRouter } from "@/server/api/trpc";
 import { meRouter } from "./routers/me";
 import { orgRouter } from "./routers/org";
@@ -9,7 +8,6 @@ import { orgRouter } from "./routers/org";
  * All routers added in /api/routers should be manually added here.
  */
 export const appRouter = createTRPCRouter({
-    scene: sceneRouter,
     me: meRouter,
     org: orgRouter,
 });
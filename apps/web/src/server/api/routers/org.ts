@@ -1,7 +1,31 @@
+import { env } from "@/env";
 import { createTRPCRouter, protectedProcedure } from "@/server/api/trpc";
 import { db } from "@/server/db";
+import { TRPCError } from "@trpc/server";
+import { createHmac } from "crypto";
 import { z } from "zod";
 
+const VIEWER_TOKEN_TTL_SECONDS = 60 * 60;
+
+const base64Url = (input: string | Buffer) =>
+    Buffer.from(input).toString("base64url");
+
+/** Signs a HS256 JWT the relay verifies on `/sub/:org` */
+function signViewerToken(secret: string, userId: string, orgName: string) {
+    const header = base64Url(JSON.stringify({ alg: "HS256", typ: "JWT" }));
+    const payload = base64Url(
+        JSON.stringify({
+            sub: userId,
+            org: orgName,
+            exp: Math.floor(Date.now() / 1000) + VIEWER_TOKEN_TTL_SECONDS,
+        }),
+    );
+    const signature = base64Url(
+        createHmac("sha256", secret).update(`${header}.${payload}`).digest(),
+    );
+    return `${header}.${payload}.${signature}`;
+}
+
 export const orgRouter = createTRPCRouter({
     get: protectedProcedure
         .input(
@@ -29,4 +53,29 @@ export const orgRouter = createTRPCRouter({
                 description: org.description,
             };
         }),
+    viewerToken: protectedProcedure
+        .input(z.object({ orgName: z.string() }))
+        .query(async ({ ctx, input }) => {
+            const userId = ctx.session.user.id;
+            // Owners and members of the org may watch it
+            const membership: { id: bigint }[] = await db.$queryRaw`
+                SELECT org.id
+                FROM ORG_Org org
+                LEFT JOIN ORG_OrgMember member
+                    ON member.orgId = org.id AND member.ownerId = ${userId}
+                WHERE org.name = ${input.orgName}
+                    AND (org.ownerId = ${userId} OR member.ownerId IS NOT NULL)
+                LIMIT 1;
+            `;
+            if (!membership[0]) {
+                throw new TRPCError({ code: "FORBIDDEN" });
+            }
+
+            if (!env.RELAY_VIEWER_TOKEN_SECRET) return null;
+            return signViewerToken(
+                env.RELAY_VIEWER_TOKEN_SECRET,
+                userId,
+                input.orgName,
+            );
+        }),
 });
@@ -1,7 +1,3 @@
-import { z } from "zod";
-
-import { createTRPCRouter, publicProcedure } from "@/server/api/trpc";
-
 export type Scene = {
     name: string;
     items: SceneItem[];
@@ -13,6 +9,7 @@ export type SceneItem = {
     position: [number, number, number];
     rotation: [number, number, number];
     color: string;
+    scale?: [number, number, number];
 };
 
 export const MeshType = {
@@ -23,13 +20,3 @@ export const MeshType = {
 } as const;
 
 type MeshType = (typeof MeshType)[keyof typeof MeshType];
-
-export const sceneRouter = createTRPCRouter({
-    getSceneByOrg: publicProcedure
-        .input(z.object({ orgName: z.string() }))
-        .query(({ input }) => {
-            return fetch(
-                `http://0.0.0.0:3002/scene/${encodeURIComponent(input.orgName)}`,
-            ).then((res): Promise<Scene> => res.json());
-        }),
-});